@@ -0,0 +1,56 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicU16, Ordering},
+};
+
+use librespot::{
+    connect::ConnectConfig,
+    core::{Error, config::DeviceType},
+    playback::{config::MixerConfig, mixer::Mixer},
+};
+
+use crate::settings::{ConnectSettings, Settings};
+
+/// Lets Spotify Connect clients read and change our volume. Connect volumes go up to `u16::MAX`, ours to 100.
+/// The sink applies the volume itself, so this doesn't provide a soft volume.
+pub struct ConnectMixer {
+    volume: Arc<AtomicU16>,
+}
+
+impl ConnectMixer {
+    pub fn new(volume: Arc<AtomicU16>) -> Self {
+        Self { volume }
+    }
+}
+
+impl Mixer for ConnectMixer {
+    fn open(_config: MixerConfig) -> Result<Self, Error> {
+        Ok(Self::new(Arc::new(AtomicU16::new(
+            Settings::current().player.volume,
+        ))))
+    }
+
+    fn volume(&self) -> u16 {
+        (self.volume.load(Ordering::Relaxed) as u32 * u16::MAX as u32 / 100) as u16
+    }
+
+    fn set_volume(&self, volume: u16) {
+        let volume = volume_to_percent(volume);
+        self.volume.store(volume, Ordering::Relaxed);
+        Settings::current_mut().player.volume = volume;
+    }
+}
+
+pub fn connect_config(settings: &ConnectSettings, mixer: &ConnectMixer) -> ConnectConfig {
+    ConnectConfig {
+        name: settings.device_name.clone(),
+        device_type: DeviceType::Computer,
+        initial_volume: mixer.volume(),
+        ..Default::default()
+    }
+}
+
+/// Connect volume to our 0-100 scale, for telling the UI.
+pub fn volume_to_percent(volume: u16) -> u16 {
+    (volume as u32 * 100).div_ceil(u16::MAX as u32) as u16
+}
@@ -0,0 +1,141 @@
+use std::f64::consts::FRAC_PI_2;
+
+use librespot::playback::{NUM_CHANNELS, SAMPLE_RATE};
+
+pub const MAX_CROSSFADE_MS: u32 = 12_000;
+
+#[derive(Debug, PartialEq)]
+enum CrossfadeState {
+    Passthrough,
+    /// The outgoing track is about to end, hold its tail back instead of playing it
+    Capturing,
+    /// The next track has been loaded, fade the held back tail out over its head
+    Mixing {
+        mix_position: usize,
+    },
+}
+
+/// Crossfades consecutive tracks inside the sink.
+///
+/// librespot only ever decodes one track at a time, but it decodes faster than real time.
+/// So when the end of a track is near we hold its last seconds back, and when the next track
+/// is loaded we mix them into the beginning of it.
+pub struct Crossfader {
+    duration_ms: u32,
+    state: CrossfadeState,
+    track_duration_ms: u32,
+    position_samples: u64,
+    tail: Vec<f64>,
+}
+
+impl Crossfader {
+    pub fn new(duration_ms: u32) -> Self {
+        Self {
+            duration_ms: duration_ms.min(MAX_CROSSFADE_MS),
+            state: CrossfadeState::Passthrough,
+            track_duration_ms: 0,
+            position_samples: 0,
+            tail: Vec::new(),
+        }
+    }
+
+    pub fn set_duration(&mut self, duration_ms: u32) {
+        self.duration_ms = duration_ms.min(MAX_CROSSFADE_MS);
+    }
+
+    pub fn track_changed(&mut self, track_duration_ms: u32) {
+        self.track_duration_ms = track_duration_ms;
+        self.position_samples = 0;
+    }
+
+    /// Re-syncs our idea of where we are in the track with what the player reports.
+    pub fn sync_position(&mut self, position_ms: u32) {
+        if self.state != CrossfadeState::Capturing {
+            self.position_samples = Self::ms_to_samples(position_ms) as u64;
+        }
+    }
+
+    /// Called when the next track is loaded, it will be mixed with the tail we're holding on to (if any).
+    pub fn begin_mix(&mut self) {
+        if self.state == CrossfadeState::Capturing && !self.tail.is_empty() {
+            self.state = CrossfadeState::Mixing { mix_position: 0 };
+        }
+    }
+
+    /// Drops the tail, for when it makes no sense to play it anymore (e.g. after seeking).
+    pub fn cancel(&mut self) {
+        self.state = CrossfadeState::Passthrough;
+        self.tail.clear();
+    }
+
+    /// Takes whatever tail we are holding on to, faded out, so it can be played before the sink stops.
+    pub fn take_tail(&mut self) -> Vec<f64> {
+        if self.state != CrossfadeState::Capturing {
+            self.cancel();
+            return Vec::new();
+        }
+
+        let mut tail = std::mem::take(&mut self.tail);
+        let frame_count = (tail.len() / NUM_CHANNELS as usize).max(1);
+        for (frame_index, frame) in tail.chunks_exact_mut(NUM_CHANNELS as usize).enumerate() {
+            let fade_out = (frame_index as f64 / frame_count as f64 * FRAC_PI_2).cos();
+            frame.iter_mut().for_each(|sample| *sample *= fade_out);
+        }
+        self.state = CrossfadeState::Passthrough;
+        tail
+    }
+
+    /// Takes decoded samples and returns the ones to play right now.
+    pub fn process(&mut self, mut samples: Vec<f64>) -> Vec<f64> {
+        match &mut self.state {
+            CrossfadeState::Passthrough => {
+                self.position_samples += samples.len() as u64;
+                if self.duration_ms > 0
+                    && self.track_duration_ms > self.duration_ms
+                    && self.remaining_ms() <= self.duration_ms
+                {
+                    log::debug!(
+                        "Holding back the last {}ms for crossfading",
+                        self.remaining_ms()
+                    );
+                    self.tail.clear();
+                    self.state = CrossfadeState::Capturing;
+                }
+                samples
+            }
+            CrossfadeState::Capturing => {
+                self.tail.extend(samples);
+                // If the track turned out longer than we thought, play the excess rather than holding on to it
+                let max_tail_len = Self::ms_to_samples(self.duration_ms);
+                if self.tail.len() > max_tail_len {
+                    self.tail.drain(..self.tail.len() - max_tail_len).collect()
+                } else {
+                    Vec::new()
+                }
+            }
+            CrossfadeState::Mixing { mix_position } => {
+                self.position_samples += samples.len() as u64;
+                let frame_count = self.tail.len() / NUM_CHANNELS as usize;
+                for (sample, tail_sample) in samples.iter_mut().zip(&self.tail[*mix_position..]) {
+                    let t = (*mix_position / NUM_CHANNELS as usize) as f64 / frame_count as f64;
+                    *sample = tail_sample * (t * FRAC_PI_2).cos() + *sample * (t * FRAC_PI_2).sin();
+                    *mix_position += 1;
+                }
+                if *mix_position >= self.tail.len() {
+                    self.cancel();
+                }
+                samples
+            }
+        }
+    }
+
+    fn remaining_ms(&self) -> u32 {
+        let position_ms = self.position_samples * 1000 / (SAMPLE_RATE as u64 * NUM_CHANNELS as u64);
+        self.track_duration_ms
+            .saturating_sub(position_ms.min(u32::MAX as u64) as u32)
+    }
+
+    fn ms_to_samples(ms: u32) -> usize {
+        ms as usize * SAMPLE_RATE as usize / 1000 * NUM_CHANNELS as usize
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    f64::consts::PI,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use librespot::playback::{NUM_CHANNELS, SAMPLE_RATE};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::settings::EqualizerSettings;
+
+/// Center frequencies of the classic Winamp equalizer sliders.
+pub const BAND_FREQUENCIES: [f64; BAND_COUNT] = [
+    60.0, 170.0, 310.0, 600.0, 1000.0, 3000.0, 6000.0, 12000.0, 14000.0, 16000.0,
+];
+pub const BAND_COUNT: usize = 10;
+pub const MAX_GAIN_DB: f32 = 12.0;
+
+// Roughly one octave wide, which is what the Winamp sliders sound like.
+const BAND_Q: f64 = 1.41;
+
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+impl Biquad {
+    /// Peaking EQ filter from the RBJ audio EQ cookbook.
+    fn peaking(frequency: f64, gain_db: f32, q: f64) -> Self {
+        let a = 10f64.powf(gain_db as f64 / 40.0);
+        let w0 = 2.0 * PI * frequency / SAMPLE_RATE as f64;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos_w0 = w0.cos();
+
+        let a0 = 1.0 + alpha / a;
+        Self {
+            b0: (1.0 + alpha * a) / a0,
+            b1: (-2.0 * cos_w0) / a0,
+            b2: (1.0 - alpha * a) / a0,
+            a1: (-2.0 * cos_w0) / a0,
+            a2: (1.0 - alpha / a) / a0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct BiquadState {
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64,
+}
+
+impl BiquadState {
+    fn process(&mut self, filter: &Biquad, x: f64) -> f64 {
+        let y = filter.b0 * x + filter.b1 * self.x1 + filter.b2 * self.x2
+            - filter.a1 * self.y1
+            - filter.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+pub struct Equalizer {
+    enabled: bool,
+    preamp: f64,
+    filters: [Biquad; BAND_COUNT],
+    states: [[BiquadState; BAND_COUNT]; NUM_CHANNELS as usize],
+}
+
+impl Equalizer {
+    pub fn new(settings: &EqualizerSettings) -> Self {
+        let mut equalizer = Self {
+            enabled: settings.enabled,
+            preamp: 1.0,
+            filters: [Biquad::peaking(1000.0, 0.0, BAND_Q); BAND_COUNT],
+            states: Default::default(),
+        };
+        equalizer.set_preamp(settings.preamp_db);
+        for (band, gain_db) in settings.bands_db.iter().enumerate() {
+            equalizer.set_band(band, *gain_db);
+        }
+        equalizer
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled && !self.enabled {
+            // Don't let stale filter memory from the last time we were enabled leak into the output
+            self.states = Default::default();
+        }
+        self.enabled = enabled;
+    }
+
+    pub fn set_preamp(&mut self, gain_db: f32) {
+        self.preamp = 10f64.powf(clamp_gain(gain_db) as f64 / 20.0);
+    }
+
+    pub fn set_band(&mut self, band: usize, gain_db: f32) {
+        if let Some(frequency) = BAND_FREQUENCIES.get(band) {
+            self.filters[band] = Biquad::peaking(*frequency, clamp_gain(gain_db), BAND_Q);
+        }
+    }
+
+    /// Filters interleaved samples in place.
+    pub fn process(&mut self, samples: &mut [f64]) {
+        if !self.enabled {
+            return;
+        }
+
+        for frame in samples.chunks_exact_mut(NUM_CHANNELS as usize) {
+            for (sample, states) in frame.iter_mut().zip(self.states.iter_mut()) {
+                let mut value = *sample * self.preamp;
+                for (filter, state) in self.filters.iter().zip(states.iter_mut()) {
+                    value = state.process(filter, value);
+                }
+                *sample = value.clamp(-1.0, 1.0);
+            }
+        }
+    }
+}
+
+pub fn clamp_gain(gain_db: f32) -> f32 {
+    gain_db.clamp(-MAX_GAIN_DB, MAX_GAIN_DB)
+}
+
+#[derive(Debug, Error)]
+pub enum PresetFileError {
+    #[error("Could not access preset file ({e:?})")]
+    Io { e: std::io::Error },
+
+    #[error("Not a Winamp EQ preset file")]
+    InvalidHeader,
+
+    #[error("Preset file ends in the middle of a preset")]
+    Truncated,
+}
+
+/// A named equalizer setting as stored in Winamp `.eqf` files and `winamp.q1` libraries.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EqPreset {
+    pub name: String,
+    pub preamp_db: f32,
+    pub bands_db: [f32; BAND_COUNT],
+}
+
+// Both `.eqf` files and `winamp.q1` libraries share this layout, an `.eqf` file just holds a single preset.
+const PRESET_FILE_HEADER: &[u8] = b"Winamp EQ library file v1.1\x1a!--";
+const PRESET_NAME_LENGTH: usize = 257;
+const PRESET_LENGTH: usize = PRESET_NAME_LENGTH + BAND_COUNT + 1;
+// Sliders are stored top to bottom, 0 being +12dB and 63 being -12dB.
+const PRESET_VALUE_MAX: f32 = 63.0;
+
+fn preset_value_to_db(value: u8) -> f32 {
+    MAX_GAIN_DB - (value.min(PRESET_VALUE_MAX as u8) as f32 / PRESET_VALUE_MAX) * 2.0 * MAX_GAIN_DB
+}
+
+fn db_to_preset_value(gain_db: f32) -> u8 {
+    ((MAX_GAIN_DB - clamp_gain(gain_db)) / (2.0 * MAX_GAIN_DB) * PRESET_VALUE_MAX).round() as u8
+}
+
+pub fn read_presets(mut reader: impl Read) -> Result<Vec<EqPreset>, PresetFileError> {
+    let mut data = Vec::new();
+    reader
+        .read_to_end(&mut data)
+        .map_err(|e| PresetFileError::Io { e })?;
+
+    let presets = data
+        .strip_prefix(PRESET_FILE_HEADER)
+        .ok_or(PresetFileError::InvalidHeader)?;
+    if presets.len() % PRESET_LENGTH != 0 {
+        return Err(PresetFileError::Truncated);
+    }
+
+    Ok(presets
+        .chunks_exact(PRESET_LENGTH)
+        .map(|preset| {
+            let (name, values) = preset.split_at(PRESET_NAME_LENGTH);
+            let name_end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+            let mut bands_db = [0.0; BAND_COUNT];
+            for (band_db, value) in bands_db.iter_mut().zip(values) {
+                *band_db = preset_value_to_db(*value);
+            }
+            EqPreset {
+                name: String::from_utf8_lossy(&name[..name_end]).into_owned(),
+                preamp_db: preset_value_to_db(values[BAND_COUNT]),
+                bands_db,
+            }
+        })
+        .collect())
+}
+
+pub fn write_presets(mut writer: impl Write, presets: &[EqPreset]) -> Result<(), PresetFileError> {
+    let mut data = PRESET_FILE_HEADER.to_vec();
+    for preset in presets {
+        let mut name = [0u8; PRESET_NAME_LENGTH];
+        // Always leave room for the terminating NUL
+        let name_length = preset.name.len().min(PRESET_NAME_LENGTH - 1);
+        name[..name_length].copy_from_slice(&preset.name.as_bytes()[..name_length]);
+        data.extend_from_slice(&name);
+        data.extend(
+            preset
+                .bands_db
+                .iter()
+                .map(|band_db| db_to_preset_value(*band_db)),
+        );
+        data.push(db_to_preset_value(preset.preamp_db));
+    }
+    writer
+        .write_all(&data)
+        .and_then(|_| writer.flush())
+        .map_err(|e| PresetFileError::Io { e })
+}
+
+pub fn load_presets(path: &Path) -> Result<Vec<EqPreset>, PresetFileError> {
+    read_presets(BufReader::new(
+        File::open(path).map_err(|e| PresetFileError::Io { e })?,
+    ))
+}
+
+pub fn save_presets(path: &Path, presets: &[EqPreset]) -> Result<(), PresetFileError> {
+    write_presets(
+        BufWriter::new(File::create(path).map_err(|e| PresetFileError::Io { e })?),
+        presets,
+    )
+}
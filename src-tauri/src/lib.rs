@@ -1,19 +1,30 @@
 use std::sync::Arc;
 
-use librespot::playback::player::PlayerEvent;
+use librespot::{
+    metadata::audio::AudioFileFormat,
+    playback::{NUM_CHANNELS, SAMPLE_RATE, player::PlayerEvent},
+};
 use serde::{Deserialize, Serialize};
-use spotify::{SessionError, SpotifyPlayer};
+use spotify::{QueuePosition, SessionError, SharedPlayer, SpotifyPlayer};
 use tauri::{AppHandle, Emitter, Listener, Manager};
 use thiserror::Error;
 
-use crate::spotify::SpotifySession;
+use crate::{settings::Settings, spotify::SpotifySession};
 mod app_window;
+mod connect;
+mod crossfade;
+mod equalizer;
+mod metadata_cache;
 mod oauth;
 mod player_window;
 mod playlist_window;
+mod recorder;
+mod scheduler;
 mod settings;
 mod sink;
 pub mod spotify;
+mod supervisor;
+mod timestretch;
 mod visualizer;
 
 #[derive(Debug, Error)]
@@ -29,6 +40,13 @@ enum StartError {
     LoginFailed { e: SessionError },
 }
 
+#[derive(Clone, Serialize)]
+struct StreamInfo {
+    bitrate: u16,
+    sample_rate: u32,
+    channels: u8,
+}
+
 #[derive(Clone, Serialize)]
 enum SpotiampPlayerEvent {
     Stopped { uri: String },
@@ -38,6 +56,11 @@ enum SpotiampPlayerEvent {
     PositionChanged { uri: String, position_ms: u32 },
     Seeked { uri: String, position_ms: u32 },
     Playing { uri: String, position_ms: u32 },
+    StreamInfo(StreamInfo),
+    QueuePosition { index: usize, uri: String },
+    Preloading { uri: String },
+    PlaybackRate { rate: f32 },
+    VolumeChanged { volume: u16 },
 }
 
 impl SpotiampPlayerEvent {
@@ -89,6 +112,34 @@ impl SpotiampPlayerEvent {
                 uri: track_id.to_uri().expect("a valid uri"),
                 position_ms,
             }),
+            PlayerEvent::Preloading { track_id } => Some(Self::Preloading {
+                uri: track_id.to_uri().expect("a valid uri"),
+            }),
+            // Only a Spotify Connect client changes the volume this way
+            PlayerEvent::VolumeChanged { volume } => Some(Self::VolumeChanged {
+                volume: connect::volume_to_percent(volume),
+            }),
+            PlayerEvent::TrackChanged { audio_item } => {
+                SpotifyPlayer::stream_format(&audio_item.files)
+                    .and_then(Self::format_bitrate)
+                    .map(|bitrate| {
+                        Self::StreamInfo(StreamInfo {
+                            bitrate,
+                            sample_rate: SAMPLE_RATE,
+                            channels: NUM_CHANNELS,
+                        })
+                    })
+            }
+            _ => None,
+        }
+    }
+
+    fn format_bitrate(format: AudioFileFormat) -> Option<u16> {
+        match format {
+            AudioFileFormat::OGG_VORBIS_96 | AudioFileFormat::MP3_96 => Some(96),
+            AudioFileFormat::OGG_VORBIS_160 | AudioFileFormat::MP3_160 => Some(160),
+            AudioFileFormat::MP3_256 => Some(256),
+            AudioFileFormat::OGG_VORBIS_320 | AudioFileFormat::MP3_320 => Some(320),
             _ => None,
         }
     }
@@ -100,6 +151,21 @@ enum PlayerWindowEvent {
     DragEnded,
 }
 
+/// Recordings get one file per track, so start a new one when a track plays and close it when it ends.
+fn split_recording(player: &SharedPlayer, player_event: &PlayerEvent) {
+    match player_event {
+        PlayerEvent::Playing { track_id, .. } => {
+            if let Err(e) = player.record_track(track_id) {
+                log::error!("Could not record track ({e:?})");
+            }
+        }
+        PlayerEvent::EndOfTrack { .. } | PlayerEvent::Stopped { .. } => {
+            player.finish_recorded_track();
+        }
+        _ => {}
+    }
+}
+
 async fn start_app(app_handle: &AppHandle) -> Result<(), StartError> {
     let session = SpotifySession::default();
     session
@@ -112,13 +178,29 @@ async fn start_app(app_handle: &AppHandle) -> Result<(), StartError> {
             window_name: "Player".to_string(),
             e,
         })?;
-    let player = Arc::new(tokio::sync::Mutex::new(SpotifyPlayer::new(session)));
+    let player = Arc::new(SpotifyPlayer::new(session));
+    let connect_enabled = Settings::current().connect.enabled;
+    if connect_enabled && let Err(e) = player.start_connect().await {
+        log::error!("Could not start Spotify Connect ({e:?})");
+    }
+    player.resume();
 
     app_handle.manage(player.clone());
+    scheduler::start(app_handle.clone(), player.clone());
+    supervisor::start(app_handle.clone(), player.clone());
+    let mut queue_channel = player.get_queue_channel();
+    let queue_window = player_window.clone();
+    tauri::async_runtime::spawn(async move {
+        while let Some(QueuePosition { index, uri }) = queue_channel.recv().await {
+            let queue_event = SpotiampPlayerEvent::QueuePosition { index, uri };
+            let _ = queue_window.emit("player", queue_event);
+        }
+    });
     tauri::async_runtime::spawn(async move {
-        let mut channel = player.lock().await.get_player_event_channel();
+        let mut channel = player.get_player_event_channel();
 
         while let Some(player_event) = channel.recv().await {
+            split_recording(&player, &player_event);
             if let Some(player_event) = SpotiampPlayerEvent::from_player_event(player_event) {
                 let _ = player_window.emit("player", player_event);
             }
@@ -134,27 +216,78 @@ pub fn run() {
         .plugin(tauri_plugin_dialog::init())
         .invoke_handler(tauri::generate_handler![
             player_window::get_track_metadata,
+            player_window::get_tracks_metadata,
+            player_window::get_episode_metadata,
+            player_window::get_cover_art,
             player_window::load_track,
             player_window::get_track_ids,
             player_window::play,
             player_window::pause,
+            player_window::next,
+            player_window::previous,
+            player_window::set_shuffle,
+            player_window::set_repeat,
             player_window::stop,
             player_window::get_player_settings,
             player_window::set_volume,
+            player_window::set_bitrate,
+            player_window::set_balance,
+            player_window::set_crossfade,
+            player_window::set_fade_duration,
+            player_window::set_playback_rate,
+            player_window::get_playback_rate,
             player_window::set_double_size,
+            player_window::get_output_settings,
+            player_window::list_audio_devices,
+            player_window::set_audio_device,
+            player_window::list_audio_backends,
+            player_window::set_audio_backend,
+            player_window::get_recording_settings,
+            player_window::set_recording_directory,
+            player_window::start_recording,
+            player_window::stop_recording,
+            player_window::get_normalisation_settings,
+            player_window::set_normalisation_settings,
+            player_window::get_account_settings,
+            player_window::get_web_api_token,
+            player_window::set_login_method,
+            player_window::switch_account,
+            player_window::add_account,
+            player_window::logout,
+            player_window::get_connect_settings,
+            player_window::set_connect,
+            player_window::get_scheduler_settings,
+            player_window::set_sleep_timer,
+            player_window::cancel_sleep_timer,
+            player_window::set_alarm,
+            player_window::cancel_alarm,
+            player_window::get_equalizer_settings,
+            player_window::set_eq_enabled,
+            player_window::set_eq_preamp,
+            player_window::set_eq_band,
+            player_window::apply_eq_preset,
+            player_window::load_eq_presets,
+            player_window::save_eq_presets,
             player_window::take_latest_spectrum,
             player_window::seek,
             player_window::set_playlist_window_visible,
             playlist_window::get_playlist_settings,
             playlist_window::set_uris,
+            playlist_window::jump_to,
+            playlist_window::get_queue_position,
+            playlist_window::set_artist_expansion,
             playlist_window::set_playlist_inner_size,
         ])
         .setup(|app| {
             let app_handle = app.handle().clone();
+            let listener_app_handle = app_handle.clone();
             app_handle.listen("playerWindow", move |event| {
                 match serde_json::from_str::<PlayerWindowEvent>(event.payload()) {
                     Ok(e) => match e {
                         PlayerWindowEvent::CloseRequested => {
+                            if let Some(player) = listener_app_handle.try_state::<SharedPlayer>() {
+                                player.save_resume_position();
+                            }
                             std::process::exit(0);
                         }
                         PlayerWindowEvent::DragEnded => {}
@@ -166,9 +299,18 @@ pub fn run() {
                 }
             });
             tauri::async_runtime::spawn(async move {
-                if let Err(e) = start_app(&app_handle).await {
-                    log::error!("Failed to start ({e:?})");
-                    app_handle.exit(1);
+                match start_app(&app_handle).await {
+                    Ok(()) => {}
+                    Err(StartError::LoginFailed {
+                        e: SessionError::LoginCancelled,
+                    }) => {
+                        log::info!("Login cancelled, exiting");
+                        std::process::exit(0);
+                    }
+                    Err(e) => {
+                        log::error!("Failed to start ({e:?})");
+                        app_handle.exit(1);
+                    }
                 }
             });
 
@@ -0,0 +1,120 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::PathBuf,
+    sync::{
+        Mutex, MutexGuard, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{player_window::TrackMetadata, scheduler::unix_time_secs, settings::get_config_dir};
+
+/// Track metadata hardly ever changes, but availability and popularity do
+const TTL_SECS: u64 = 7 * 24 * 60 * 60;
+/// Expired metadata is still better than nothing when offline, but not forever
+const MAX_AGE_SECS: u64 = 90 * 24 * 60 * 60;
+/// Lookups come in batches, they are saved together
+const SAVE_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize)]
+struct CachedTrackMetadata {
+    fetched_at: u64,
+    metadata: TrackMetadata,
+}
+
+/// Track metadata by URI, kept next to the librespot cache so big playlists load quickly and work offline.
+#[derive(Default, Serialize, Deserialize)]
+pub struct MetadataCache {
+    tracks: HashMap<String, CachedTrackMetadata>,
+}
+
+fn get_cache_file_path() -> Option<PathBuf> {
+    get_config_dir().map(|config_dir| config_dir.join("metadata.json"))
+}
+
+impl MetadataCache {
+    pub fn current() -> MutexGuard<'static, MetadataCache> {
+        static MEM: OnceLock<Mutex<MetadataCache>> = OnceLock::new();
+        MEM.get_or_init(|| Mutex::new(MetadataCache::load()))
+            .lock()
+            .unwrap()
+    }
+
+    fn load() -> MetadataCache {
+        let Some(cache_file_path) = get_cache_file_path() else {
+            return MetadataCache::default();
+        };
+        File::open(&cache_file_path)
+            .map_err(|e| format!("Could not open file ({e:?})"))
+            .and_then(|f| {
+                serde_json::from_reader(BufReader::new(f))
+                    .map_err(|e| format!("Could not deserialize file ({e:?})"))
+            })
+            .unwrap_or_else(|e| {
+                log::info!("Starting with an empty metadata cache ({cache_file_path:?}): {e}");
+                MetadataCache::default()
+            })
+    }
+
+    /// Saves the cache a little while after it changed, without holding it up (or the async runtime) while writing.
+    pub fn save_soon() {
+        static SAVE_SCHEDULED: AtomicBool = AtomicBool::new(false);
+        if SAVE_SCHEDULED.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        tauri::async_runtime::spawn(async {
+            tokio::time::sleep(SAVE_DELAY).await;
+            SAVE_SCHEDULED.store(false, Ordering::Relaxed);
+            let contents = MetadataCache::current().serialize();
+            let _ = tauri::async_runtime::spawn_blocking(move || Self::save(contents)).await;
+        });
+    }
+
+    /// Drops what is too old to be of use and serializes the rest.
+    fn serialize(&mut self) -> Result<Vec<u8>, String> {
+        let now = unix_time_secs();
+        self.tracks
+            .retain(|_, cached| now < cached.fetched_at + MAX_AGE_SECS);
+        serde_json::to_vec(self).map_err(|e| format!("Could not serialize ({e:?})"))
+    }
+
+    fn save(contents: Result<Vec<u8>, String>) {
+        let Some(cache_file_path) = get_cache_file_path() else {
+            return;
+        };
+        if let Err(e) = contents.and_then(|contents| {
+            std::fs::write(&cache_file_path, contents)
+                .map_err(|e| format!("Could not write file ({e:?})"))
+        }) {
+            log::error!("Failed to save metadata cache: {:?}", e);
+        }
+    }
+
+    /// Only returns metadata that hasn't expired yet.
+    pub fn get(&self, uri: &str) -> Option<TrackMetadata> {
+        self.tracks
+            .get(uri)
+            .filter(|cached| unix_time_secs() < cached.fetched_at + TTL_SECS)
+            .map(|cached| cached.metadata.clone())
+    }
+
+    /// Returns metadata even if it has expired, which beats nothing when offline.
+    pub fn get_stale(&self, uri: &str) -> Option<TrackMetadata> {
+        self.tracks.get(uri).map(|cached| cached.metadata.clone())
+    }
+
+    pub fn insert(&mut self, uri: &str, metadata: TrackMetadata) {
+        self.tracks.insert(
+            uri.to_string(),
+            CachedTrackMetadata {
+                fetched_at: unix_time_secs(),
+                metadata,
+            },
+        );
+    }
+}
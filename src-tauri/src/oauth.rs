@@ -1,18 +1,26 @@
 use oauth2::{
-    AuthUrl, AuthorizationCode, ClientId, CsrfToken, PkceCodeChallenge, RedirectUrl, Scope,
-    TokenUrl, basic::BasicClient, reqwest,
+    AuthUrl, AuthorizationCode, ClientId, CsrfToken, PkceCodeChallenge, RedirectUrl, RefreshToken,
+    Scope, TokenResponse, TokenUrl, basic::BasicClient, reqwest,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter},
+    net::{SocketAddr, TcpListener},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
-use serde::Deserialize;
-use std::net::{SocketAddr, TcpListener};
 use thiserror::Error;
-use tokio::sync::broadcast::Sender;
+use tokio::sync::{Notify, oneshot};
 use url::{ParseError, Url};
 
+use crate::scheduler::unix_time_secs;
 use axum::{
     Router,
     extract::{Query, State},
     http::StatusCode,
-    response::{Html, IntoResponse},
+    response::{Html, IntoResponse, Response},
     routing::get,
 };
 
@@ -35,6 +43,27 @@ pub enum OAuthError {
 
     #[error("Failed to setup local authentication server ({e})")]
     CouldNotStartServer { e: std::io::Error },
+
+    #[error("The login was cancelled")]
+    AccessDenied,
+
+    #[error("Spotify refused the login ({error})")]
+    AuthorizationFailed { error: String },
+
+    #[error("The login callback didn't come from the login we started")]
+    CsrfMismatch,
+
+    #[error("The login callback is missing the authorization code")]
+    MalformedCallback,
+
+    #[error("Nobody logged in within {timeout:?}")]
+    Timeout { timeout: Duration },
+
+    #[error("There is no refresh token, the user has to log in again")]
+    NoRefreshToken,
+
+    #[error("Failed to refresh the access token ({e})")]
+    Refresh { e: String },
 }
 type Client = oauth2::Client<
     oauth2::StandardErrorResponse<oauth2::basic::BasicErrorResponseType>,
@@ -52,18 +81,113 @@ type Client = oauth2::Client<
     oauth2::EndpointSet,
 >;
 
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Tokens are refreshed a bit before they actually expire
+const EXPIRY_MARGIN_SECS: u64 = 60;
+
+/// What we keep of a token response, so the Web API can be used without logging in again.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OAuthToken {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    /// Unix time in seconds
+    pub expires_at: Option<u64>,
+    pub scopes: Vec<String>,
+}
+
+/// Kept next to the credentials of the account it belongs to
+const TOKEN_FILE_NAME: &str = "oauth_token.json";
+
+impl OAuthToken {
+    /// Spotify doesn't always hand out a new refresh token when refreshing, the old one is still good then.
+    fn from_response(response: &TokenType, previous_refresh_token: Option<String>) -> Self {
+        Self {
+            access_token: response.access_token().secret().clone(),
+            refresh_token: response
+                .refresh_token()
+                .map(|token| token.secret().clone())
+                .or(previous_refresh_token),
+            expires_at: response
+                .expires_in()
+                .map(|expires_in| unix_time_secs() + expires_in.as_secs()),
+            scopes: response
+                .scopes()
+                .map(|scopes| scopes.iter().map(|scope| scope.to_string()).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| unix_time_secs() + EXPIRY_MARGIN_SECS >= expires_at)
+    }
+
+    pub fn load(directory: &Path) -> Option<Self> {
+        File::open(directory.join(TOKEN_FILE_NAME))
+            .ok()
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+    }
+
+    /// The refresh token is as good as a password, so on unix only the user can read the file (also when
+    /// it was already there with looser permissions). On Windows it isn't protected any further than the
+    /// profile directory it is in.
+    pub fn save(&self, directory: &Path) {
+        let token_file_path = directory.join(TOKEN_FILE_NAME);
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        if let Err(e) = options
+            .open(&token_file_path)
+            .map_err(|e| format!("Could not create file ({e:?})"))
+            .and_then(|file| {
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    file.set_permissions(std::fs::Permissions::from_mode(0o600))
+                        .map_err(|e| format!("Could not restrict permissions ({e:?})"))?;
+                }
+                serde_json::to_writer(BufWriter::new(file), self)
+                    .map_err(|e| format!("Could not serialize ({e:?})"))
+            })
+        {
+            log::error!("Failed to save OAuth token: {:?}", e);
+        }
+    }
+
+    pub fn remove(directory: &Path) {
+        let token_file_path = directory.join(TOKEN_FILE_NAME);
+        if let Err(e) = std::fs::remove_file(&token_file_path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            log::error!("Could not remove OAuth token {token_file_path:?} ({e:?})");
+        }
+    }
+}
+
 pub struct OAuthFlow {
     auth_url: Url,
     socket_addr: SocketAddr,
     pub client: Client,
     pkce_verifier: oauth2::PkceCodeVerifier,
+    csrf_token: CsrfToken,
+    timeout: Duration,
 }
 
 type TokenType =
     oauth2::StandardTokenResponse<oauth2::EmptyExtraTokenFields, oauth2::basic::BasicTokenType>;
 
 impl OAuthFlow {
-    pub fn new(auth_url: &str, token_url: &str, client_id: &str) -> Result<Self, OAuthError> {
+    pub fn new(
+        auth_url: &str,
+        token_url: &str,
+        client_id: &str,
+        scopes: &[&str],
+    ) -> Result<Self, OAuthError> {
         log::debug!("Creating OAuth flow");
         let auth_url =
             AuthUrl::new(auth_url.to_string()).map_err(|e| OAuthError::InvalidAuthUri {
@@ -92,9 +216,9 @@ impl OAuthFlow {
             .set_redirect_uri(redirect_url);
 
         let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
-        let (auth_url, _) = client
+        let (auth_url, csrf_token) = client
             .authorize_url(CsrfToken::new_random)
-            .add_scopes(vec![Scope::new("streaming".to_string())])
+            .add_scopes(scopes.iter().map(|scope| Scope::new(scope.to_string())))
             .set_pkce_challenge(pkce_challenge)
             .url();
 
@@ -103,9 +227,17 @@ impl OAuthFlow {
             socket_addr,
             client,
             pkce_verifier,
+            csrf_token,
+            timeout: DEFAULT_TIMEOUT,
         })
     }
 
+    /// How long to wait for the user to log in before giving up.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
     fn get_available_addr() -> SocketAddr {
         let listener = TcpListener::bind("127.0.0.1:0").unwrap();
         let addr = listener.local_addr().unwrap();
@@ -113,69 +245,386 @@ impl OAuthFlow {
         addr
     }
 
-    pub async fn start(self) -> Result<TokenType, OAuthError> {
-        let (tx, mut rx) = tokio::sync::broadcast::channel::<AuthorizationCode>(1);
-
-        #[derive(Deserialize)]
-        struct Params {
-            #[serde(default)]
-            code: String,
-        }
-
+    /// The caller saves the token once it knows which account it belongs to.
+    pub async fn start(self) -> Result<OAuthToken, OAuthError> {
+        let (tx, rx) = oneshot::channel();
+        let shutdown = Arc::new(Notify::new());
         let app = Router::new()
-            .route(
-                "/login",
-                get(
-                    |Query(params): Query<Params>,
-                     State(mut tx): State<Option<Sender<AuthorizationCode>>>| async move {
-                        if let Some(tx) = tx.take() {
-                            let _ = tx.send(AuthorizationCode::new(params.code));
-                            Html("<body></body>").into_response()
-                        } else {
-                            (
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                "Redirect URL already used",
-                            )
-                                .into_response()
-                        }
-                    },
-                ),
-            )
-            .with_state(Some(tx.clone()));
+            .route("/login", get(handle_callback))
+            .with_state(CallbackState {
+                csrf_token: self.csrf_token.clone(),
+                tx: Arc::new(Mutex::new(Some(tx))),
+                shutdown: shutdown.clone(),
+            });
 
         log::info!("Waiting for OAuth redirect server to receive callback...");
         let listener = tokio::net::TcpListener::bind(self.socket_addr)
             .await
             .map_err(|e| OAuthError::CouldNotStartServer { e })?;
-        axum::serve(listener, app)
-            .with_graceful_shutdown({
-                let mut rx = tx.subscribe();
-                async move {
-                    let _ = rx.recv().await;
-                }
-            })
+        let server = axum::serve(listener, app)
+            .with_graceful_shutdown(async move { shutdown.notified().await });
+        let code = tokio::time::timeout(self.timeout, async {
+            server
+                .await
+                .map_err(|e| OAuthError::CouldNotStartServer { e })?;
+            rx.await
+                .map_err(|e| OAuthError::Recv { e: e.to_string() })?
+        })
+        .await
+        .map_err(|_| OAuthError::Timeout {
+            timeout: self.timeout,
+        })??;
+
+        log::debug!("Doing the exchange...");
+        let token = self
+            .client
+            .exchange_code(code)
+            .set_pkce_verifier(self.pkce_verifier)
+            .request_async(&Self::http_client())
             .await
-            .map_err(|e| OAuthError::CouldNotStartServer { e })?;
+            .map_err(|e| OAuthError::ExchangeCode { e: e.to_string() })?;
+        Ok(OAuthToken::from_response(&token, None))
+    }
 
-        let code = rx
-            .recv()
+    /// Trades the saved refresh token for a new access token, without bothering the user.
+    pub async fn refresh(&self, directory: &Path) -> Result<OAuthToken, OAuthError> {
+        let refresh_token = OAuthToken::load(directory)
+            .and_then(|token| token.refresh_token)
+            .ok_or(OAuthError::NoRefreshToken)?;
+        log::debug!("Refreshing the access token...");
+        let response = self
+            .client
+            .exchange_refresh_token(&RefreshToken::new(refresh_token.clone()))
+            .request_async(&Self::http_client())
             .await
-            .map_err(|e| OAuthError::Recv { e: e.to_string() })?;
-        log::debug!("Doing the exchange...");
-        let http_client = reqwest::ClientBuilder::new()
+            .map_err(|e| OAuthError::Refresh { e: e.to_string() })?;
+        let token = OAuthToken::from_response(&response, Some(refresh_token));
+        token.save(directory);
+        Ok(token)
+    }
+
+    /// The saved access token, refreshed first if it has expired.
+    pub async fn access_token(&self, directory: &Path) -> Result<String, OAuthError> {
+        match OAuthToken::load(directory) {
+            Some(token) if !token.is_expired() => Ok(token.access_token),
+            _ => Ok(self.refresh(directory).await?.access_token),
+        }
+    }
+
+    fn http_client() -> reqwest::Client {
+        reqwest::ClientBuilder::new()
             // Following redirects opens the client up to SSRF vulnerabilities.
             .redirect(reqwest::redirect::Policy::none())
             .build()
-            .expect("Client should build");
-        self.client
-            .exchange_code(code)
-            .set_pkce_verifier(self.pkce_verifier)
-            .request_async(&http_client)
-            .await
-            .map_err(|e| OAuthError::Recv { e: e.to_string() })
+            .expect("Client should build")
     }
 
     pub(crate) fn get_auth_url(&self) -> String {
         self.auth_url.to_string()
     }
 }
+
+type CodeSender = oneshot::Sender<Result<AuthorizationCode, OAuthError>>;
+
+#[derive(Clone)]
+struct CallbackState {
+    csrf_token: CsrfToken,
+    /// Taken by the first callback, any later ones are turned away
+    tx: Arc<Mutex<Option<CodeSender>>>,
+    shutdown: Arc<Notify>,
+}
+
+#[derive(Deserialize)]
+struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+impl CallbackParams {
+    fn has_state(&self, csrf_token: &CsrfToken) -> bool {
+        self.state.as_deref() == Some(csrf_token.secret().as_str())
+    }
+
+    fn into_code(self) -> Result<AuthorizationCode, OAuthError> {
+        match (self.code, self.error) {
+            (_, Some(error)) if error == "access_denied" => Err(OAuthError::AccessDenied),
+            (_, Some(error)) => Err(OAuthError::AuthorizationFailed { error }),
+            (Some(code), None) if !code.is_empty() => Ok(AuthorizationCode::new(code)),
+            _ => Err(OAuthError::MalformedCallback),
+        }
+    }
+}
+
+async fn handle_callback(
+    Query(params): Query<CallbackParams>,
+    State(state): State<CallbackState>,
+) -> Response {
+    // Anyone can make a request to the redirect server, so one that isn't ours doesn't end the login
+    if !params.has_state(&state.csrf_token) {
+        return (
+            StatusCode::BAD_REQUEST,
+            callback_page("Login failed", &OAuthError::CsrfMismatch.to_string()),
+        )
+            .into_response();
+    }
+
+    let Some(tx) = state.tx.lock().unwrap().take() else {
+        return (
+            StatusCode::GONE,
+            callback_page("Already used", "This login link has already been used."),
+        )
+            .into_response();
+    };
+
+    let code = params.into_code();
+    let response = match &code {
+        Ok(_) => (
+            StatusCode::OK,
+            callback_page(
+                "Logged in",
+                "Spotiamp is starting, this window will close by itself.",
+            ),
+        ),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            callback_page("Login failed", &e.to_string()),
+        ),
+    };
+    let _ = tx.send(code);
+    state.shutdown.notify_one();
+    response.into_response()
+}
+
+fn callback_page(title: &str, message: &str) -> Html<String> {
+    let escape = |text: &str| {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    };
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>{title}</title></head>
+<body style="font-family: sans-serif; text-align: center; margin-top: 4em;">
+<h1>{title}</h1>
+<p>{message}</p>
+</body>
+</html>"#,
+        title = escape(title),
+        message = escape(message),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::header, routing::post};
+
+    const CODE: &str = "the-code";
+
+    /// A token endpoint that only hands out a token for `CODE` with a PKCE verifier.
+    async fn start_token_server() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route(
+            "/token",
+            post(|body: String| async move {
+                let form: Vec<(String, String)> = url::form_urlencoded::parse(body.as_bytes())
+                    .into_owned()
+                    .collect();
+                let has = |key: &str, value: Option<&str>| {
+                    form.iter()
+                        .any(|(k, v)| k == key && value.is_none_or(|value| v == value))
+                };
+                if !has("grant_type", Some("authorization_code"))
+                    || !has("code", Some(CODE))
+                    || !has("code_verifier", None)
+                {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        [(header::CONTENT_TYPE, "application/json")],
+                        r#"{"error":"invalid_grant"}"#,
+                    );
+                }
+                (
+                    StatusCode::OK,
+                    [(header::CONTENT_TYPE, "application/json")],
+                    r#"{"access_token":"access","token_type":"bearer","expires_in":3600,"refresh_token":"refresh","scope":"streaming user-read-email"}"#,
+                )
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{addr}/token")
+    }
+
+    async fn flow() -> OAuthFlow {
+        let token_url = start_token_server().await;
+        OAuthFlow::new(
+            "https://accounts.example.com/authorize",
+            &token_url,
+            "client-id",
+            &["streaming"],
+        )
+        .unwrap()
+    }
+
+    /// Keeps trying until the redirect server is up.
+    async fn callback(addr: SocketAddr, query: &str) -> reqwest::StatusCode {
+        let url = format!("http://{addr}/login?{query}");
+        for _ in 0..50 {
+            match reqwest::get(&url).await {
+                Ok(response) => return response.status(),
+                Err(e) if e.is_connect() => tokio::time::sleep(Duration::from_millis(20)).await,
+                Err(e) => panic!("callback request failed ({e:?})"),
+            }
+        }
+        panic!("the redirect server never came up");
+    }
+
+    /// Starts the flow in the background, returning where to send the callback and its state.
+    fn start(
+        flow: OAuthFlow,
+    ) -> (
+        SocketAddr,
+        String,
+        tokio::task::JoinHandle<Result<OAuthToken, OAuthError>>,
+    ) {
+        let addr = flow.socket_addr;
+        let state = flow.csrf_token.secret().clone();
+        (addr, state, tokio::spawn(flow.start()))
+    }
+
+    #[tokio::test]
+    async fn exchanges_the_code_for_a_token() {
+        let (addr, state, login) = start(flow().await);
+
+        let status = callback(addr, &format!("code={CODE}&state={state}")).await;
+        assert_eq!(status, reqwest::StatusCode::OK);
+
+        let token = login.await.unwrap().unwrap();
+        assert_eq!(token.access_token, "access");
+        assert_eq!(token.refresh_token.as_deref(), Some("refresh"));
+        assert_eq!(token.scopes, ["streaming", "user-read-email"]);
+        assert!(!token.is_expired());
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_token_endpoint_rejects_the_code() {
+        let (addr, state, login) = start(flow().await);
+
+        let status = callback(addr, &format!("code=another-code&state={state}")).await;
+        assert_eq!(status, reqwest::StatusCode::OK);
+
+        assert!(matches!(
+            login.await.unwrap(),
+            Err(OAuthError::ExchangeCode { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn keeps_waiting_after_a_wrong_or_missing_state() {
+        let (addr, state, login) = start(flow().await);
+
+        let status = callback(addr, &format!("code={CODE}&state=not-{state}")).await;
+        assert_eq!(status, reqwest::StatusCode::BAD_REQUEST);
+        let status = callback(addr, &format!("code={CODE}")).await;
+        assert_eq!(status, reqwest::StatusCode::BAD_REQUEST);
+        assert!(!login.is_finished());
+
+        let status = callback(addr, &format!("code={CODE}&state={state}")).await;
+        assert_eq!(status, reqwest::StatusCode::OK);
+        assert!(login.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn fails_when_access_is_denied() {
+        let (addr, state, login) = start(flow().await);
+
+        let status = callback(addr, &format!("error=access_denied&state={state}")).await;
+        assert_eq!(status, reqwest::StatusCode::BAD_REQUEST);
+
+        assert!(matches!(
+            login.await.unwrap(),
+            Err(OAuthError::AccessDenied)
+        ));
+    }
+
+    #[tokio::test]
+    async fn fails_without_a_code() {
+        let (addr, state, login) = start(flow().await);
+
+        let status = callback(addr, &format!("state={state}")).await;
+        assert_eq!(status, reqwest::StatusCode::BAD_REQUEST);
+
+        assert!(matches!(
+            login.await.unwrap(),
+            Err(OAuthError::MalformedCallback)
+        ));
+    }
+
+    #[tokio::test]
+    async fn turns_away_a_second_callback() {
+        let csrf_token = CsrfToken::new("state".to_string());
+        let (tx, rx) = oneshot::channel();
+        let state = CallbackState {
+            csrf_token: csrf_token.clone(),
+            tx: Arc::new(Mutex::new(Some(tx))),
+            shutdown: Arc::new(Notify::new()),
+        };
+        let params = || CallbackParams {
+            code: Some(CODE.to_string()),
+            state: Some("state".to_string()),
+            error: None,
+        };
+
+        let first = handle_callback(Query(params()), State(state.clone())).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        let second = handle_callback(Query(params()), State(state)).await;
+        assert_eq!(second.status(), StatusCode::GONE);
+
+        assert_eq!(rx.await.unwrap().unwrap().secret(), CODE);
+    }
+
+    #[tokio::test]
+    async fn times_out_without_a_callback() {
+        let flow = flow().await.with_timeout(Duration::from_millis(100));
+
+        assert!(matches!(
+            flow.start().await,
+            Err(OAuthError::Timeout { .. })
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_the_user_can_read_a_saved_token() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory =
+            std::env::temp_dir().join(format!("spotiamp-oauth-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let token_file_path = directory.join(TOKEN_FILE_NAME);
+        // Left behind by an older version, readable by everyone
+        File::create(&token_file_path).unwrap();
+        std::fs::set_permissions(&token_file_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let token = OAuthToken {
+            access_token: "access".to_string(),
+            refresh_token: Some("refresh".to_string()),
+            expires_at: None,
+            scopes: vec![],
+        };
+        token.save(&directory);
+
+        let mode = std::fs::metadata(&token_file_path)
+            .unwrap()
+            .permissions()
+            .mode();
+        let loaded = OAuthToken::load(&directory);
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(loaded.unwrap().refresh_token.as_deref(), Some("refresh"));
+    }
+}
@@ -1,51 +1,158 @@
-use librespot::{core::SpotifyUri, metadata::Track};
-use serde::Serialize;
-use tauri::{AppHandle, Emitter, Manager, State, WebviewWindow};
+use std::path::PathBuf;
+
+use futures_util::{StreamExt, stream};
+use librespot::{
+    core::{FileId, SpotifyUri},
+    metadata::{
+        Episode, Track,
+        image::{Image, ImageSize},
+    },
+};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Manager, State, WebviewWindow, ipc::Response};
 
 use crate::{
-    app_window, playlist_window,
-    settings::{PlayerSettings, Settings},
-    spotify::SharedPlayer,
+    SpotiampPlayerEvent, app_window,
+    crossfade::MAX_CROSSFADE_MS,
+    equalizer::{self, BAND_COUNT, EqPreset},
+    metadata_cache::MetadataCache,
+    playlist_window, scheduler,
+    settings::{
+        AccountSettings, AlarmSettings, ConnectSettings, EqualizerSettings, LoginMethod,
+        NormalisationSettings, OutputSettings, PlayerSettings, RecordingSettings, RepeatMode,
+        SchedulerSettings, Settings, SleepTimerSettings,
+    },
+    sink,
+    spotify::{SessionError, SharedPlayer, SpotifyPlayer, SpotifySession},
+    timestretch,
 };
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum CoverSize {
+    Small,
+    Default,
+    Large,
+    XLarge,
+}
+
+impl From<ImageSize> for CoverSize {
+    fn from(size: ImageSize) -> Self {
+        match size {
+            ImageSize::SMALL => Self::Small,
+            ImageSize::LARGE => Self::Large,
+            ImageSize::XLARGE => Self::XLarge,
+            _ => Self::Default,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoverImage {
+    file_id: String,
+    size: CoverSize,
+    width: i32,
+    height: i32,
+}
+
+impl From<&Image> for CoverImage {
+    fn from(image: &Image) -> Self {
+        Self {
+            file_id: image.id.to_base16().unwrap_or_default(),
+            size: image.size.into(),
+            width: image.width,
+            height: image.height,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrackMetadata {
     uri: String,
+    /// The first of `artists`, kept for everything that only shows one
     artist: String,
+    artists: Vec<String>,
     name: String,
+    album: String,
+    album_artists: Vec<String>,
+    disc_number: i32,
+    track_number: i32,
+    /// Unix time in milliseconds
+    released_at: i64,
+    popularity: i32,
+    explicit: bool,
     duration: u32,
     unavailable: bool,
+    covers: Vec<CoverImage>,
 }
+
 impl TrackMetadata {
-    pub fn new(
-        track_uri: &SpotifyUri,
-        artist: &str,
-        name: &str,
-        duration: u32,
-        unavailable: bool,
-    ) -> Self {
-        Self {
-            unavailable,
-            uri: track_uri.to_uri().expect("a valid uri"),
-            artist: artist.to_string(),
-            name: name.to_string(),
-            duration,
-        }
+    pub fn title(&self) -> String {
+        format!("{} - {}", self.artist, self.name)
     }
 }
+
 impl From<&Track> for TrackMetadata {
     fn from(track: &Track) -> Self {
-        Self::new(
-            &track.id,
-            &track
-                .artists
+        let artists: Vec<String> = track
+            .artists
+            .iter()
+            .map(|artist| artist.name.clone())
+            .collect();
+        Self {
+            uri: track.id.to_uri().expect("a valid uri"),
+            artist: artists
                 .first()
-                .map(|artist| artist.name.clone())
+                .cloned()
                 .unwrap_or("Unknown Artist".to_string()),
-            &track.name,
-            track.duration as u32,
-            !track.restrictions.is_empty() && track.alternatives.is_empty(),
-        )
+            artists,
+            name: track.name.clone(),
+            album: track.album.name.clone(),
+            album_artists: track
+                .album
+                .artists
+                .iter()
+                .map(|artist| artist.name.clone())
+                .collect(),
+            disc_number: track.disc_number,
+            track_number: track.number,
+            released_at: track.album.date.as_timestamp_ms(),
+            popularity: track.popularity,
+            explicit: track.is_explicit,
+            duration: track.duration as u32,
+            unavailable: !track.restrictions.is_empty() && track.alternatives.is_empty(),
+            covers: track.album.covers.iter().map(CoverImage::from).collect(),
+        }
+    }
+}
+
+/// What `TrackMetadata` is for tracks, for podcast episodes.
+#[derive(Debug, Clone, Serialize)]
+pub struct EpisodeMetadata {
+    uri: String,
+    show: String,
+    name: String,
+    duration: u32,
+    /// Unix time in milliseconds
+    published_at: i64,
+    unavailable: bool,
+}
+
+impl EpisodeMetadata {
+    pub fn title(&self) -> String {
+        format!("{} - {}", self.show, self.name)
+    }
+}
+
+impl From<&Episode> for EpisodeMetadata {
+    fn from(episode: &Episode) -> Self {
+        Self {
+            uri: episode.id.to_uri().expect("a valid uri"),
+            show: episode.show_name.clone(),
+            name: episode.name.clone(),
+            duration: episode.duration as u32,
+            published_at: episode.publish_time.as_timestamp_ms(),
+            unavailable: !episode.restrictions.is_empty(),
+        }
     }
 }
 
@@ -56,26 +163,348 @@ pub fn get_player_settings() -> PlayerSettings {
 
 #[tauri::command]
 pub async fn set_volume(volume: u16, player: State<'_, SharedPlayer>) -> Result<(), ()> {
-    player.lock().await.set_volume(volume);
+    player.set_volume(volume);
     Settings::current_mut().player.volume = volume;
     Ok(())
 }
 
+#[tauri::command]
+pub async fn set_balance(balance: i16, player: State<'_, SharedPlayer>) -> Result<(), ()> {
+    let balance = balance.clamp(-100, 100);
+    player.set_balance(balance);
+    Settings::current_mut().player.balance = balance;
+    Ok(())
+}
+
 #[tauri::command]
 pub fn set_double_size(active: bool) {
     Settings::current_mut().player.double_size_active = active;
 }
 
+#[tauri::command]
+pub async fn set_bitrate(bitrate: u16, player: State<'_, SharedPlayer>) -> Result<(), String> {
+    if !matches!(bitrate, 96 | 160 | 320) {
+        return Err(format!("Unsupported bitrate {bitrate}kbps"));
+    }
+    Settings::current_mut().player.bitrate = bitrate;
+    player
+        .rebuild_player()
+        .await
+        .map_err(|e| format!("Failed to apply the bitrate ({e:?})"))
+}
+
+#[tauri::command]
+pub fn get_output_settings() -> OutputSettings {
+    Settings::current().output.clone()
+}
+
+#[tauri::command]
+pub fn list_audio_devices() -> Vec<String> {
+    sink::list_output_devices()
+}
+
+#[tauri::command]
+pub fn list_audio_backends() -> Vec<String> {
+    sink::list_backends()
+}
+
+#[tauri::command]
+pub async fn set_audio_backend(
+    backend: Option<String>,
+    device: Option<String>,
+    player: State<'_, SharedPlayer>,
+) -> Result<(), String> {
+    if let Some(backend) = &backend
+        && !sink::list_backends().contains(backend)
+    {
+        return Err(format!("Audio backend '{backend}' is not available"));
+    }
+    let output = OutputSettings { backend, device };
+    player.set_audio_output(output.clone());
+    Settings::current_mut().output = output;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_audio_device(
+    device: Option<String>,
+    player: State<'_, SharedPlayer>,
+) -> Result<(), ()> {
+    let output = OutputSettings {
+        device,
+        ..Settings::current().output.clone()
+    };
+    player.set_audio_output(output.clone());
+    Settings::current_mut().output = output;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_recording_settings() -> RecordingSettings {
+    Settings::current().recording.clone()
+}
+
+#[tauri::command]
+pub fn set_recording_directory(path: Option<PathBuf>) {
+    Settings::current_mut().recording.output_directory = path;
+}
+
+#[tauri::command]
+pub async fn start_recording(player: State<'_, SharedPlayer>) -> Result<(), String> {
+    let output_directory = Settings::current()
+        .recording
+        .get_output_directory()
+        .ok_or("No directory to record to")?;
+    player
+        .start_recording(output_directory)
+        .map_err(|e| format!("Failed to start recording ({e:?})"))
+}
+
+#[tauri::command]
+pub async fn stop_recording(player: State<'_, SharedPlayer>) -> Result<(), ()> {
+    player.stop_recording();
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_crossfade(duration_ms: u32, player: State<'_, SharedPlayer>) -> Result<(), ()> {
+    let duration_ms = duration_ms.min(MAX_CROSSFADE_MS);
+    player.set_crossfade(duration_ms);
+    Settings::current_mut().player.crossfade_ms = duration_ms;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_playback_rate(
+    rate: f32,
+    player: State<'_, SharedPlayer>,
+    app_handle: AppHandle,
+) -> Result<(), String> {
+    if !(timestretch::MIN_RATE..=timestretch::MAX_RATE).contains(&rate) {
+        return Err(format!(
+            "Playback rate {rate} is outside {}..{}",
+            timestretch::MIN_RATE,
+            timestretch::MAX_RATE
+        ));
+    }
+    player.set_playback_rate(rate);
+    // Let the UI know so it can run its clock at the same speed
+    let _ = app_handle.emit("player", SpotiampPlayerEvent::PlaybackRate { rate });
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_playback_rate(player: State<'_, SharedPlayer>) -> Result<f32, ()> {
+    Ok(player.get_playback_rate())
+}
+
+#[tauri::command]
+pub async fn set_fade_duration(
+    duration_ms: u32,
+    player: State<'_, SharedPlayer>,
+) -> Result<(), ()> {
+    let duration_ms = duration_ms.min(sink::MAX_FADE_MS);
+    player.set_fade_duration(duration_ms);
+    Settings::current_mut().player.fade_ms = duration_ms;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_account_settings() -> AccountSettings {
+    Settings::current().accounts.clone()
+}
+
+/// An access token for the Spotify Web API, refreshed if needed.
+#[tauri::command]
+pub async fn get_web_api_token() -> Result<String, String> {
+    SpotifySession::web_api_token()
+        .await
+        .map_err(|e| format!("Failed to get an access token ({e:?})"))
+}
+
+#[tauri::command]
+pub fn set_login_method(login_method: LoginMethod) {
+    Settings::current_mut().accounts.login_method = login_method;
+}
+
+#[tauri::command]
+pub async fn switch_account(name: String, player: State<'_, SharedPlayer>) -> Result<(), String> {
+    player
+        .switch_account(&name)
+        .await
+        .map_err(|e| format!("Failed to switch to account {name} ({e:?})"))
+}
+
+#[tauri::command]
+pub async fn add_account(app: AppHandle, player: State<'_, SharedPlayer>) -> Result<(), String> {
+    match player.add_account(&app).await {
+        Err(SessionError::LoginCancelled) => Ok(()),
+        added => added.map_err(|e| format!("Failed to add account ({e:?})")),
+    }
+}
+
+#[tauri::command]
+pub async fn logout(app: AppHandle, player: State<'_, SharedPlayer>) -> Result<(), String> {
+    player
+        .logout(&app)
+        .await
+        .map_err(|e| format!("Failed to log out ({e:?})"))
+}
+
+#[tauri::command]
+pub fn get_connect_settings() -> ConnectSettings {
+    Settings::current().connect.clone()
+}
+
+/// Turns Spotify Connect on or off, or restarts it under a new name.
+#[tauri::command]
+pub async fn set_connect(
+    enabled: bool,
+    device_name: String,
+    player: State<'_, SharedPlayer>,
+) -> Result<(), String> {
+    Settings::current_mut().connect = ConnectSettings {
+        enabled,
+        device_name,
+    };
+    if enabled {
+        player
+            .start_connect()
+            .await
+            .map_err(|e| format!("Failed to start Spotify Connect ({e:?})"))
+    } else {
+        player.stop_connect();
+        Ok(())
+    }
+}
+
+#[tauri::command]
+pub fn get_scheduler_settings() -> SchedulerSettings {
+    Settings::current().scheduler.clone()
+}
+
+#[tauri::command]
+pub fn set_sleep_timer(minutes: u32, fade_out_secs: u32) {
+    Settings::current_mut().scheduler.sleep_timer = Some(SleepTimerSettings {
+        ends_at: scheduler::unix_time_secs() + minutes as u64 * 60,
+        fade_out_secs: fade_out_secs.min(minutes * 60),
+    });
+}
+
+#[tauri::command]
+pub fn cancel_sleep_timer() {
+    Settings::current_mut().scheduler.sleep_timer = None;
+}
+
+#[tauri::command]
+pub fn set_alarm(at: u64, uri: String, fade_in_secs: u32) -> Result<(), String> {
+    if at <= scheduler::unix_time_secs() {
+        return Err("The alarm time has already passed".to_string());
+    }
+    SpotifyUri::from_uri(&uri).map_err(|e| format!("Invalid uri '{uri}' ({e:?})"))?;
+    Settings::current_mut().scheduler.alarm = Some(AlarmSettings {
+        at,
+        uri,
+        fade_in_secs,
+    });
+    Ok(())
+}
+
+#[tauri::command]
+pub fn cancel_alarm() {
+    Settings::current_mut().scheduler.alarm = None;
+}
+
+#[tauri::command]
+pub fn get_normalisation_settings() -> NormalisationSettings {
+    Settings::current().normalisation.clone()
+}
+
+#[tauri::command]
+pub async fn set_normalisation_settings(
+    normalisation: NormalisationSettings,
+    player: State<'_, SharedPlayer>,
+) -> Result<(), String> {
+    Settings::current_mut().normalisation = normalisation;
+    // The normalisation config is fixed when librespot creates its player
+    player
+        .rebuild_player()
+        .await
+        .map_err(|e| format!("Failed to apply the normalisation settings ({e:?})"))
+}
+
+#[tauri::command]
+pub fn get_equalizer_settings() -> EqualizerSettings {
+    Settings::current().equalizer.clone()
+}
+
+#[tauri::command]
+pub async fn set_eq_enabled(enabled: bool, player: State<'_, SharedPlayer>) -> Result<(), ()> {
+    player.set_eq_enabled(enabled);
+    Settings::current_mut().equalizer.enabled = enabled;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_eq_preamp(gain_db: f32, player: State<'_, SharedPlayer>) -> Result<(), ()> {
+    let gain_db = equalizer::clamp_gain(gain_db);
+    player.set_eq_preamp(gain_db);
+    Settings::current_mut().equalizer.preamp_db = gain_db;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_eq_band(
+    band: usize,
+    gain_db: f32,
+    player: State<'_, SharedPlayer>,
+) -> Result<(), String> {
+    if band >= BAND_COUNT {
+        return Err(format!("No equalizer band {band}"));
+    }
+    let gain_db = equalizer::clamp_gain(gain_db);
+    player.set_eq_band(band, gain_db);
+    Settings::current_mut().equalizer.bands_db[band] = gain_db;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn apply_eq_preset(
+    preset: EqPreset,
+    player: State<'_, SharedPlayer>,
+) -> Result<EqualizerSettings, ()> {
+    let mut settings = Settings::current_mut();
+    settings.equalizer.preamp_db = equalizer::clamp_gain(preset.preamp_db);
+    player.set_eq_preamp(settings.equalizer.preamp_db);
+    for (band, gain_db) in preset.bands_db.iter().enumerate() {
+        settings.equalizer.bands_db[band] = equalizer::clamp_gain(*gain_db);
+        player.set_eq_band(band, settings.equalizer.bands_db[band]);
+    }
+    Ok(settings.equalizer.clone())
+}
+
+/// Reads the presets from a Winamp `.eqf` file or a `winamp.q1` library.
+#[tauri::command]
+pub fn load_eq_presets(path: PathBuf) -> Result<Vec<EqPreset>, String> {
+    equalizer::load_presets(&path)
+        .map_err(|e| format!("Failed to load EQ presets from {path:?} ({e:?})"))
+}
+
+/// Writes presets in the Winamp format, a single preset makes an `.eqf` file and several a `winamp.q1` library.
+#[tauri::command]
+pub fn save_eq_presets(path: PathBuf, presets: Vec<EqPreset>) -> Result<(), String> {
+    equalizer::save_presets(&path, &presets)
+        .map_err(|e| format!("Failed to save EQ presets to {path:?} ({e:?})"))
+}
+
 #[tauri::command]
 pub async fn take_latest_spectrum(player: State<'_, SharedPlayer>) -> Result<Vec<(f32, f32)>, ()> {
-    Ok(player.lock().await.take_latest_spectrum())
+    Ok(player.take_latest_spectrum())
 }
 
 #[tauri::command]
 pub async fn load_track(uri: &str, player: State<'_, SharedPlayer>) -> Result<(), String> {
     player
-        .lock()
-        .await
         .load_track(uri)
         .await
         .map_err(|e| format!("Failed to load track ({e:?})"))
@@ -83,16 +512,40 @@ pub async fn load_track(uri: &str, player: State<'_, SharedPlayer>) -> Result<()
 
 #[tauri::command]
 pub async fn play(player: State<'_, SharedPlayer>) -> Result<(), String> {
-    player.lock().await.play();
+    player.play();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn next(player: State<'_, SharedPlayer>) -> Result<(), ()> {
+    player.next();
+    Ok(())
+}
 
+#[tauri::command]
+pub async fn previous(player: State<'_, SharedPlayer>) -> Result<(), ()> {
+    player.previous();
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_shuffle(enabled: bool, player: State<'_, SharedPlayer>) -> Result<(), ()> {
+    player.set_shuffle(enabled);
+    Settings::current_mut().playlist.shuffle = enabled;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn set_repeat(mode: RepeatMode, player: State<'_, SharedPlayer>) -> Result<(), ()> {
+    player.set_repeat(mode);
+    Settings::current_mut().playlist.repeat = mode;
     Ok(())
 }
 
 #[tauri::command]
 pub async fn pause(player: State<'_, SharedPlayer>) -> Result<(), String> {
     player
-        .lock()
-        .await
         .pause()
         .await
         .map_err(|e| format!("Failed to pause ({e:?})"))?;
@@ -103,8 +556,6 @@ pub async fn pause(player: State<'_, SharedPlayer>) -> Result<(), String> {
 #[tauri::command]
 pub async fn stop(player: State<'_, SharedPlayer>) -> Result<(), String> {
     player
-        .lock()
-        .await
         .stop()
         .await
         .map_err(|e| format!("Failed to stop ({e:?})"))?;
@@ -112,21 +563,97 @@ pub async fn stop(player: State<'_, SharedPlayer>) -> Result<(), String> {
     Ok(())
 }
 
+/// Enough to fill a big playlist quickly without flooding Spotify with requests
+const CONCURRENT_METADATA_FETCHES: usize = 8;
+
+/// Looks in the metadata cache first, and falls back to expired metadata if Spotify can't be reached.
+async fn get_cached_track_metadata(
+    player: &SpotifyPlayer,
+    uri: String,
+) -> Result<TrackMetadata, String> {
+    let cached = MetadataCache::current().get(&uri);
+    if let Some(metadata) = cached {
+        return Ok(metadata);
+    }
+
+    let track_uri = SpotifyUri::from_uri(&uri)
+        .map_err(|e| format!("Failed to get track by uri '{uri}' ({e:?})"))?;
+    match player.get_track(track_uri).await {
+        Ok(track) => {
+            let metadata = TrackMetadata::from(&track);
+            MetadataCache::current().insert(&uri, metadata.clone());
+            Ok(metadata)
+        }
+        Err(e) => MetadataCache::current()
+            .get_stale(&uri)
+            .ok_or(format!("Could not load track ({e:?})")),
+    }
+}
+
 #[tauri::command]
 pub async fn get_track_metadata(
-    uri: &str,
+    uri: String,
     player: State<'_, SharedPlayer>,
 ) -> Result<TrackMetadata, String> {
-    Ok(TrackMetadata::from(
+    let metadata = get_cached_track_metadata(&player, uri).await?;
+    MetadataCache::save_soon();
+    Ok(metadata)
+}
+
+/// Fetches whatever isn't cached, a few at a time. Tracks that couldn't be loaded are `None`.
+#[tauri::command]
+pub async fn get_tracks_metadata(
+    uris: Vec<String>,
+    player: State<'_, SharedPlayer>,
+) -> Result<Vec<Option<TrackMetadata>>, ()> {
+    let tracks = stream::iter(uris)
+        .map(|uri| get_cached_track_metadata(&player, uri))
+        .buffered(CONCURRENT_METADATA_FETCHES)
+        .map(|metadata| metadata.inspect_err(|e| log::warn!("{e}")).ok())
+        .collect()
+        .await;
+    MetadataCache::save_soon();
+    Ok(tracks)
+}
+
+/// Returns the raw image, which the frontend turns into a blob URL.
+#[tauri::command]
+pub async fn get_cover_art(
+    file_id: &str,
+    size: CoverSize,
+    player: State<'_, SharedPlayer>,
+) -> Result<Response, String> {
+    let raw_file_id = (0..file_id.len())
+        .step_by(2)
+        .map(|i| {
+            file_id
+                .get(i..i + 2)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()
+        .filter(|raw| raw.len() == 20)
+        .ok_or(format!("'{file_id}' is not a valid file id"))?;
+
+    let image = player
+        .get_cover_art(FileId::from_raw(&raw_file_id), size)
+        .await
+        .map_err(|e| format!("Could not load cover art ({e:?})"))?;
+    Ok(Response::new(image))
+}
+
+#[tauri::command]
+pub async fn get_episode_metadata(
+    uri: &str,
+    player: State<'_, SharedPlayer>,
+) -> Result<EpisodeMetadata, String> {
+    Ok(EpisodeMetadata::from(
         &player
-            .lock()
-            .await
-            .get_track(
+            .get_episode(
                 SpotifyUri::from_uri(uri)
-                    .map_err(|e| format!("Failed to get track by uri '{uri}' ({e:?})"))?,
+                    .map_err(|e| format!("Failed to get episode by uri '{uri}' ({e:?})"))?,
             )
             .await
-            .map_err(|e| format!("Could not load track ({e:?})"))?,
+            .map_err(|e| format!("Could not load episode ({e:?})"))?,
     ))
 }
 
@@ -136,8 +663,6 @@ pub async fn get_track_ids(
     player: State<'_, SharedPlayer>,
 ) -> Result<Vec<String>, String> {
     Ok(player
-        .lock()
-        .await
         .get_track_ids(
             SpotifyUri::from_uri(uri)
                 .map_err(|e| format!("Failed to get playlist by uri '{uri}' ({e:?})"))?,
@@ -151,7 +676,7 @@ pub async fn get_track_ids(
 
 #[tauri::command]
 pub async fn seek(position_ms: u32, player: State<'_, SharedPlayer>) -> Result<(), String> {
-    player.lock().await.seek(position_ms);
+    player.seek(position_ms).await;
     Ok(())
 }
 
@@ -1,8 +1,9 @@
-use tauri::{AppHandle, LogicalPosition, WebviewWindow};
+use tauri::{AppHandle, LogicalPosition, State, WebviewWindow};
 
 use crate::{
     app_window,
-    settings::{InnerWindowSize, PlaylistSettings, Settings},
+    settings::{ArtistExpansion, InnerWindowSize, PlaylistSettings, Settings},
+    spotify::SharedPlayer,
 };
 
 #[tauri::command]
@@ -11,8 +12,31 @@ pub fn get_playlist_settings() -> PlaylistSettings {
 }
 
 #[tauri::command]
-pub fn set_uris(uris: Vec<String>) {
+pub async fn set_uris(uris: Vec<String>, player: State<'_, SharedPlayer>) -> Result<(), ()> {
+    player.set_queue_uris(uris.clone());
     Settings::current_mut().playlist.uris = uris;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn jump_to(
+    index: usize,
+    play: bool,
+    player: State<'_, SharedPlayer>,
+) -> Result<(), String> {
+    player
+        .jump_to(index, play)
+        .map_err(|e| format!("Failed to jump to track ({e:?})"))
+}
+
+#[tauri::command]
+pub async fn get_queue_position(player: State<'_, SharedPlayer>) -> Result<Option<usize>, ()> {
+    Ok(player.get_queue_position())
+}
+
+#[tauri::command]
+pub fn set_artist_expansion(artist_expansion: ArtistExpansion) {
+    Settings::current_mut().playlist.artist_expansion = artist_expansion;
 }
 
 #[tauri::command]
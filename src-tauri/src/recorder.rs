@@ -0,0 +1,175 @@
+use std::{
+    fs::{File, create_dir_all, rename},
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+use librespot::playback::{NUM_CHANNELS, SAMPLE_RATE};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RecordingError {
+    #[error("Could not create recording directory {path:?} ({e:?})")]
+    CreateDirectory { path: PathBuf, e: std::io::Error },
+
+    #[error("Could not create recording {path:?} ({e:?})")]
+    CreateFile { path: PathBuf, e: hound::Error },
+}
+
+struct Recording {
+    uri: String,
+    path: PathBuf,
+    writer: WavWriter<BufWriter<File>>,
+    /// The title found out after the file was started, it is renamed to it once finished
+    title: Option<String>,
+}
+
+/// Tees the decoded audio into one WAV file per track while recording is active.
+/// The sink feeds it samples and the player events tell it where the tracks start and end.
+#[derive(Default)]
+pub struct Recorder {
+    output_directory: Option<PathBuf>,
+    recording: Option<Recording>,
+}
+
+impl Recorder {
+    pub fn start(&mut self, output_directory: PathBuf) -> Result<(), RecordingError> {
+        create_dir_all(&output_directory).map_err(|e| RecordingError::CreateDirectory {
+            path: output_directory.clone(),
+            e,
+        })?;
+        log::info!("Recording to {output_directory:?}");
+        self.output_directory = Some(output_directory);
+        Ok(())
+    }
+
+    pub fn stop(&mut self) {
+        self.finish_track();
+        self.output_directory = None;
+        log::info!("Stopped recording");
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.output_directory.is_some()
+    }
+
+    pub fn is_recording_track(&self, uri: &str) -> bool {
+        self.recording
+            .as_ref()
+            .is_some_and(|recording| recording.uri == uri)
+    }
+
+    /// Finishes the current file and starts a new one named after the track, or after its uri until `set_title` is called.
+    pub fn start_track(&mut self, uri: &str, title: Option<&str>) -> Result<(), RecordingError> {
+        self.finish_track();
+        let Some(output_directory) = &self.output_directory else {
+            return Ok(());
+        };
+
+        let path = Self::unused_path(
+            output_directory,
+            &Self::sanitize_file_name(title.unwrap_or(uri)),
+        );
+        let writer = WavWriter::create(
+            &path,
+            WavSpec {
+                channels: NUM_CHANNELS as u16,
+                sample_rate: SAMPLE_RATE,
+                bits_per_sample: 16,
+                sample_format: SampleFormat::Int,
+            },
+        )
+        .map_err(|e| RecordingError::CreateFile {
+            path: path.clone(),
+            e,
+        })?;
+        log::debug!("Recording {uri} to {path:?}");
+        self.recording = Some(Recording {
+            uri: uri.to_string(),
+            path,
+            writer,
+            title: None,
+        });
+        Ok(())
+    }
+
+    /// Names the recording of the track once its metadata has come in, if it is still being recorded.
+    pub fn set_title(&mut self, uri: &str, title: String) {
+        if let Some(recording) = &mut self.recording
+            && recording.uri == uri
+        {
+            recording.title = Some(title);
+        }
+    }
+
+    pub fn finish_track(&mut self) {
+        let Some(recording) = self.recording.take() else {
+            return;
+        };
+        if let Err(e) = recording.writer.finalize() {
+            log::error!("Could not finish recording {:?} ({e:?})", recording.path);
+            return;
+        }
+        log::debug!("Finished recording {:?}", recording.path);
+
+        // Renamed only now, an open file can't be renamed everywhere
+        if let Some(title) = recording.title
+            && let Some(directory) = recording.path.parent()
+        {
+            let path = Self::unused_path(directory, &Self::sanitize_file_name(&title));
+            if let Err(e) = rename(&recording.path, &path) {
+                log::warn!("Could not rename {:?} to {path:?} ({e:?})", recording.path);
+            }
+        }
+    }
+
+    /// Writes interleaved samples. `attenuation` is the volume librespot has already applied, it is undone
+    /// so the recording doesn't depend on the volume slider (at zero volume there is nothing left to record).
+    pub fn write(&mut self, samples: &[f64], attenuation: f64) {
+        let Some(recording) = &mut self.recording else {
+            return;
+        };
+
+        let gain = if attenuation > 0.0 {
+            1.0 / attenuation
+        } else {
+            0.0
+        };
+        for sample in samples {
+            let sample = ((sample * gain).clamp(-1.0, 1.0) * i16::MAX as f64) as i16;
+            if let Err(e) = recording.writer.write_sample(sample) {
+                log::error!(
+                    "Failed to write to {:?}, dropping the recording ({e:?})",
+                    recording.path
+                );
+                self.recording = None;
+                return;
+            }
+        }
+    }
+
+    fn sanitize_file_name(title: &str) -> String {
+        title
+            .chars()
+            .map(|c| match c {
+                '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+                c if c.is_control() => '_',
+                c => c,
+            })
+            .collect::<String>()
+            .trim()
+            .trim_end_matches('.')
+            .to_string()
+    }
+
+    fn unused_path(directory: &Path, file_stem: &str) -> PathBuf {
+        let mut path = directory.join(format!("{file_stem}.wav"));
+        let mut copy = 1;
+        while path.exists() {
+            copy += 1;
+            path = directory.join(format!("{file_stem} ({copy}).wav"));
+        }
+        path
+    }
+}
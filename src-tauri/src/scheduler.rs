@@ -0,0 +1,160 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tauri::{AppHandle, Emitter};
+
+use crate::{settings::Settings, spotify::SharedPlayer};
+
+const TICK: Duration = Duration::from_millis(250);
+const MISSED_ALARM_SECS: u64 = 60;
+
+#[derive(Clone, PartialEq, Serialize)]
+pub enum SchedulerEvent {
+    SleepTimer { remaining_secs: u64 },
+    SleepTimerOff,
+    Alarm { uri: String, remaining_secs: u64 },
+    AlarmOff,
+}
+
+pub fn unix_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Runs the sleep timer and the alarm. Both are kept in the settings (as wall-clock times) and looked up on
+/// every tick, so they are picked up again after a restart.
+struct Scheduler {
+    app_handle: AppHandle,
+    player: SharedPlayer,
+    /// Set while turning the volume down, so it can be put back if the timer is cancelled
+    fading_out: bool,
+    fade_in: Option<(Instant, Duration)>,
+    last_sleep_timer_event: Option<SchedulerEvent>,
+    last_alarm_event: Option<SchedulerEvent>,
+}
+
+pub fn start(app_handle: AppHandle, player: SharedPlayer) {
+    let mut scheduler = Scheduler {
+        app_handle,
+        player,
+        fading_out: false,
+        fade_in: None,
+        last_sleep_timer_event: None,
+        last_alarm_event: None,
+    };
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(TICK);
+        loop {
+            interval.tick().await;
+            scheduler.tick().await;
+        }
+    });
+}
+
+impl Scheduler {
+    async fn tick(&mut self) {
+        let (sleep_timer, alarm, volume) = {
+            let settings = Settings::current();
+            (
+                settings.scheduler.sleep_timer.clone(),
+                settings.scheduler.alarm.clone(),
+                settings.player.volume,
+            )
+        };
+        let now = unix_time_secs();
+
+        match sleep_timer {
+            Some(sleep_timer) if now >= sleep_timer.ends_at => {
+                log::info!("Sleep timer ended, stopping");
+                if let Err(e) = self.player.stop().await {
+                    log::error!("Sleep timer could not stop the player ({e:?})");
+                }
+                self.fading_out = false;
+                self.player.set_faded_volume(volume);
+                Settings::current_mut().scheduler.sleep_timer = None;
+                self.emit_sleep_timer(SchedulerEvent::SleepTimerOff);
+            }
+            Some(sleep_timer) => {
+                let remaining = Duration::from_secs(sleep_timer.ends_at - now);
+                let fade_out = Duration::from_secs(sleep_timer.fade_out_secs as u64);
+                if remaining < fade_out {
+                    self.fading_out = true;
+                    let faded_volume =
+                        volume as f64 * remaining.as_secs_f64() / fade_out.as_secs_f64();
+                    self.player.set_faded_volume(faded_volume as u16);
+                }
+                self.emit_sleep_timer(SchedulerEvent::SleepTimer {
+                    remaining_secs: remaining.as_secs(),
+                });
+            }
+            None => {
+                // Cancelled half way through fading out
+                if self.fading_out {
+                    self.fading_out = false;
+                    self.player.set_faded_volume(volume);
+                }
+                self.emit_sleep_timer(SchedulerEvent::SleepTimerOff);
+            }
+        }
+
+        match alarm {
+            // Don't wake anyone up hours late because Spotiamp wasn't running at the time
+            Some(alarm) if now >= alarm.at + MISSED_ALARM_SECS => {
+                log::info!("Missed the alarm at {}, dropping it", alarm.at);
+                Settings::current_mut().scheduler.alarm = None;
+                self.emit_alarm(SchedulerEvent::AlarmOff);
+            }
+            Some(alarm) if now >= alarm.at => {
+                log::info!("Alarm going off, playing {}", alarm.uri);
+                Settings::current_mut().scheduler.alarm = None;
+                self.player.set_faded_volume(0);
+                self.fade_in = Some((
+                    Instant::now(),
+                    Duration::from_secs(alarm.fade_in_secs as u64),
+                ));
+                if let Err(e) = self.player.load_track(&alarm.uri).await {
+                    log::error!("Alarm could not play {} ({e:?})", alarm.uri);
+                }
+                self.emit_alarm(SchedulerEvent::AlarmOff);
+            }
+            Some(alarm) => self.emit_alarm(SchedulerEvent::Alarm {
+                remaining_secs: alarm.at - now,
+                uri: alarm.uri,
+            }),
+            None => self.emit_alarm(SchedulerEvent::AlarmOff),
+        }
+
+        if let Some((started_at, fade_in)) = self.fade_in {
+            let elapsed = started_at.elapsed();
+            if elapsed >= fade_in {
+                self.fade_in = None;
+                self.player.set_faded_volume(volume);
+            } else {
+                let faded_volume = volume as f64 * elapsed.as_secs_f64() / fade_in.as_secs_f64();
+                self.player.set_faded_volume(faded_volume as u16);
+            }
+        }
+    }
+
+    /// Only tells the UI when something changed, which is about once a second for a countdown.
+    fn emit_sleep_timer(&mut self, event: SchedulerEvent) {
+        Self::emit_if_changed(&self.app_handle, &mut self.last_sleep_timer_event, event);
+    }
+
+    fn emit_alarm(&mut self, event: SchedulerEvent) {
+        Self::emit_if_changed(&self.app_handle, &mut self.last_alarm_event, event);
+    }
+
+    fn emit_if_changed(
+        app_handle: &AppHandle,
+        last_event: &mut Option<SchedulerEvent>,
+        event: SchedulerEvent,
+    ) {
+        if last_event.as_ref() != Some(&event) {
+            let _ = app_handle.emit("scheduler", event.clone());
+            *last_event = Some(event);
+        }
+    }
+}
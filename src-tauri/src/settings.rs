@@ -8,10 +8,12 @@ use std::{
     sync::{OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard},
 };
 
-use directories::ProjectDirs;
+use directories::{ProjectDirs, UserDirs};
 use serde::{Deserialize, Serialize};
 use tauri::LogicalPosition;
 
+use crate::equalizer::BAND_COUNT;
+
 pub fn get_config_dir() -> Option<PathBuf> {
     let path =
         ProjectDirs::from("org.darkbits", "", "spotiamp").map(|pd| pd.config_dir().to_path_buf());
@@ -107,10 +109,32 @@ impl WindowState {
     }
 }
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, Hash)]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    All,
+    One,
+}
+
+/// What dropping an artist on the playlist adds
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, Hash)]
+pub enum ArtistExpansion {
+    #[default]
+    TopTracks,
+    Discography,
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize, Hash)]
 pub struct PlaylistSettings {
     pub window_state: WindowState,
     pub uris: Vec<String>,
+    #[serde(default)]
+    pub shuffle: bool,
+    #[serde(default)]
+    pub repeat: RepeatMode,
+    #[serde(default)]
+    pub artist_expansion: ArtistExpansion,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash)]
@@ -118,24 +142,240 @@ pub struct PlayerSettings {
     pub window_state: WindowState,
     pub double_size_active: bool,
     pub volume: u16,
+    /// -100 is fully left, 100 is fully right
+    #[serde(default)]
+    pub balance: i16,
+    /// Streaming bitrate in kbps, one of 96, 160 or 320
+    #[serde(default = "default_bitrate")]
+    pub bitrate: u16,
+    /// How long consecutive tracks overlap, 0 turns crossfading off
+    #[serde(default)]
+    pub crossfade_ms: u32,
+    /// Fade length when pausing, stopping and seeking, 0 cuts the audio right away
+    #[serde(default = "default_fade_ms")]
+    pub fade_ms: u32,
     pub show_playlist: bool,
 }
 
+fn default_bitrate() -> u16 {
+    320
+}
+
+fn default_fade_ms() -> u32 {
+    100
+}
+
 impl Default for PlayerSettings {
     fn default() -> Self {
         Self {
             window_state: Default::default(),
             double_size_active: Default::default(),
             volume: 80,
+            balance: 0,
+            bitrate: default_bitrate(),
+            crossfade_ms: 0,
+            fade_ms: default_fade_ms(),
             show_playlist: true,
         }
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EqualizerSettings {
+    pub enabled: bool,
+    pub preamp_db: f32,
+    pub bands_db: [f32; BAND_COUNT],
+}
+
+impl Default for EqualizerSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            preamp_db: 0.0,
+            bands_db: [0.0; BAND_COUNT],
+        }
+    }
+}
+
+// Floats don't implement `Hash`, so hash their bit patterns to still detect changes when auto saving
+impl Hash for EqualizerSettings {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.enabled.hash(state);
+        self.preamp_db.to_bits().hash(state);
+        for band_db in self.bands_db {
+            band_db.to_bits().hash(state);
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Hash, PartialEq)]
+pub enum NormalisationTypeSetting {
+    #[default]
+    Auto,
+    Album,
+    Track,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Hash, PartialEq)]
+pub enum NormalisationMethodSetting {
+    Basic,
+    #[default]
+    Dynamic,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NormalisationSettings {
+    pub enabled: bool,
+    pub normalisation_type: NormalisationTypeSetting,
+    pub method: NormalisationMethodSetting,
+    pub pregain_db: f64,
+    pub threshold_dbfs: f64,
+    pub attack_ms: u64,
+    pub release_ms: u64,
+    pub knee_db: f64,
+}
+
+impl Default for NormalisationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            normalisation_type: Default::default(),
+            method: Default::default(),
+            pregain_db: 0.0,
+            threshold_dbfs: -2.0,
+            attack_ms: 5,
+            release_ms: 100,
+            knee_db: 5.0,
+        }
+    }
+}
+
+impl Hash for NormalisationSettings {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.enabled.hash(state);
+        self.normalisation_type.hash(state);
+        self.method.hash(state);
+        self.pregain_db.to_bits().hash(state);
+        self.threshold_dbfs.to_bits().hash(state);
+        self.attack_ms.hash(state);
+        self.release_ms.hash(state);
+        self.knee_db.to_bits().hash(state);
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Hash, PartialEq)]
+pub struct OutputSettings {
+    /// Name of the librespot audio backend, `None` means the first one compiled in
+    #[serde(default)]
+    pub backend: Option<String>,
+    /// Passed on to the backend: the output device for rodio, ALSA and PulseAudio,
+    /// the file for pipe and the shell command for subprocess. `None` means the backend's default.
+    pub device: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Hash)]
+pub struct RecordingSettings {
+    /// Where recordings end up, `None` means a Spotiamp folder in the user's music directory
+    pub output_directory: Option<PathBuf>,
+}
+
+impl RecordingSettings {
+    pub fn get_output_directory(&self) -> Option<PathBuf> {
+        self.output_directory.clone().or_else(|| {
+            UserDirs::new()
+                .and_then(|dirs| dirs.audio_dir().map(|dir| dir.join("Spotiamp")))
+                .or_else(|| get_config_dir().map(|dir| dir.join("recordings")))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Hash)]
+pub struct SleepTimerSettings {
+    /// Unix time in seconds
+    pub ends_at: u64,
+    pub fade_out_secs: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Hash)]
+pub struct AlarmSettings {
+    /// Unix time in seconds
+    pub at: u64,
+    pub uri: String,
+    pub fade_in_secs: u32,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Hash)]
+pub struct SchedulerSettings {
+    pub sleep_timer: Option<SleepTimerSettings>,
+    pub alarm: Option<AlarmSettings>,
+}
+
+/// Where playback was at when Spotiamp was closed, so the next session can pick up from there.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Hash)]
+pub struct ResumeSettings {
+    pub uri: Option<String>,
+    /// Position in the playlist, the same track might be in there more than once
+    pub index: Option<usize>,
+    pub position_ms: u32,
+    pub playing: bool,
+}
+
+/// Spotiamp as a Spotify Connect device that can be controlled from other Spotify clients.
+#[derive(Debug, Clone, Serialize, Deserialize, Hash)]
+pub struct ConnectSettings {
+    pub enabled: bool,
+    pub device_name: String,
+}
+
+impl Default for ConnectSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            device_name: "Spotiamp".to_string(),
+        }
+    }
+}
+
+/// How to get credentials when there are none cached.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, Hash)]
+pub enum LoginMethod {
+    /// Log in on accounts.spotify.com in a webview
+    #[default]
+    OAuth,
+    /// Wait for another Spotify client on the network to hand over its credentials
+    Zeroconf,
+}
+
+/// The Spotify accounts that have logged in, each with its own credentials in `accounts/<name>` in the config dir.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Hash)]
+pub struct AccountSettings {
+    /// `None` until the first login, credentials are kept directly in the config dir until then
+    pub active: Option<String>,
+    pub names: Vec<String>,
+    #[serde(default)]
+    pub login_method: LoginMethod,
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize, Hash)]
 pub struct Settings {
     pub player: PlayerSettings,
     pub playlist: PlaylistSettings,
+    #[serde(default)]
+    pub equalizer: EqualizerSettings,
+    #[serde(default)]
+    pub normalisation: NormalisationSettings,
+    #[serde(default)]
+    pub output: OutputSettings,
+    #[serde(default)]
+    pub recording: RecordingSettings,
+    #[serde(default)]
+    pub resume: ResumeSettings,
+    #[serde(default)]
+    pub scheduler: SchedulerSettings,
+    #[serde(default)]
+    pub accounts: AccountSettings,
+    #[serde(default)]
+    pub connect: ConnectSettings,
 }
 
 impl Settings {
@@ -1,60 +1,320 @@
-use std::sync::atomic::AtomicU16;
+use std::f64::consts::FRAC_PI_2;
+use std::sync::atomic::{AtomicI16, AtomicU16};
 use std::sync::{Arc, Mutex};
 
 use librespot::playback::audio_backend::{self, Sink, SinkResult};
 use librespot::playback::config::AudioFormat;
 use librespot::playback::convert::Converter;
 use librespot::playback::decoder::AudioPacket;
+use librespot::playback::{NUM_CHANNELS, SAMPLE_RATE};
 
+use crate::crossfade::Crossfader;
+use crate::equalizer::Equalizer;
+use crate::recorder::Recorder;
+use crate::settings::{OutputSettings, Settings};
+use crate::timestretch::TimeStretcher;
 use crate::visualizer::Visualizer;
 
+/// State shared between the sink (living on the librespot player thread) and the rest of the app.
+/// It outlives the sink, so it is kept when the player is rebuilt.
+#[derive(Clone)]
+pub struct SinkState {
+    pub visualizer: Arc<Mutex<Visualizer>>,
+    pub equalizer: Arc<Mutex<Equalizer>>,
+    pub volume: Arc<AtomicU16>,
+    pub balance: Arc<AtomicI16>,
+    pub output: Arc<Mutex<OutputSettings>>,
+    pub recorder: Arc<Mutex<Recorder>>,
+    pub crossfader: Arc<Mutex<Crossfader>>,
+    pub fader: Arc<Mutex<Fader>>,
+    pub time_stretcher: Arc<Mutex<TimeStretcher>>,
+}
+
+impl SinkState {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            visualizer: Arc::new(Mutex::new(Visualizer::new())),
+            equalizer: Arc::new(Mutex::new(Equalizer::new(&settings.equalizer))),
+            volume: Arc::new(AtomicU16::new(settings.player.volume)),
+            balance: Arc::new(AtomicI16::new(settings.player.balance)),
+            output: Arc::new(Mutex::new(settings.output.clone())),
+            recorder: Arc::new(Mutex::new(Recorder::default())),
+            crossfader: Arc::new(Mutex::new(Crossfader::new(settings.player.crossfade_ms))),
+            fader: Arc::new(Mutex::new(Fader::new(settings.player.fade_ms))),
+            time_stretcher: Arc::new(Mutex::new(TimeStretcher::new(1.0))),
+        }
+    }
+}
+
+pub const MAX_FADE_MS: u32 = 500;
+/// Seeking only needs a short fade to get rid of the pop
+const MICRO_FADE_MS: u32 = 50;
+
+/// A gain envelope for fading in and out around pause, stop and seek so the audio doesn't click.
+pub struct Fader {
+    duration_ms: u32,
+    gain: f64,
+    target: f64,
+    step: f64,
+}
+
+impl Fader {
+    pub fn new(duration_ms: u32) -> Self {
+        Self {
+            duration_ms: duration_ms.min(MAX_FADE_MS),
+            gain: 1.0,
+            target: 1.0,
+            step: 0.0,
+        }
+    }
+
+    pub fn set_duration(&mut self, duration_ms: u32) {
+        self.duration_ms = duration_ms.min(MAX_FADE_MS);
+    }
+
+    /// Starts fading to silence, returns how long it will take.
+    pub fn fade_out(&mut self) -> u32 {
+        self.fade_to(0.0, self.duration_ms)
+    }
+
+    pub fn fade_in(&mut self) {
+        self.fade_to(1.0, self.duration_ms);
+    }
+
+    pub fn micro_fade_out(&mut self) -> u32 {
+        self.fade_to(0.0, self.duration_ms.min(MICRO_FADE_MS))
+    }
+
+    pub fn micro_fade_in(&mut self) {
+        self.fade_to(1.0, self.duration_ms.min(MICRO_FADE_MS));
+    }
+
+    /// Back to full volume right away.
+    pub fn reset(&mut self) {
+        self.gain = 1.0;
+        self.target = 1.0;
+    }
+
+    fn fade_to(&mut self, target: f64, duration_ms: u32) -> u32 {
+        let frames = duration_ms as f64 * SAMPLE_RATE as f64 / 1000.0;
+        self.target = target;
+        if frames < 1.0 {
+            self.gain = target;
+            return 0;
+        }
+        self.step = (target - self.gain).abs() / frames;
+        duration_ms
+    }
+
+    fn apply(&mut self, samples: &mut [f64]) {
+        if self.gain == 1.0 && self.target == 1.0 {
+            return;
+        }
+
+        for frame in samples.chunks_exact_mut(NUM_CHANNELS as usize) {
+            if self.gain < self.target {
+                self.gain = (self.gain + self.step).min(self.target);
+            } else if self.gain > self.target {
+                self.gain = (self.gain - self.step).max(self.target);
+            }
+            frame.iter_mut().for_each(|sample| *sample *= self.gain);
+        }
+    }
+}
+
 pub struct SpotiampSink {
     backend_delegate: Box<dyn Sink>,
-    visualizer: Arc<Mutex<Visualizer>>,
-    volume: Arc<AtomicU16>,
+    format: AudioFormat,
+    output: OutputSettings,
+    running: bool,
+    state: SinkState,
     scratch: Vec<f32>,
 }
 
+pub fn list_backends() -> Vec<String> {
+    audio_backend::BACKENDS
+        .iter()
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+#[cfg(feature = "rodio-backend")]
+pub fn list_output_devices() -> Vec<String> {
+    match cpal::default_host().output_devices() {
+        Ok(devices) => devices.filter_map(|device| device.name().ok()).collect(),
+        Err(e) => {
+            log::warn!("Could not list output devices ({e:?})");
+            vec![]
+        }
+    }
+}
+
+/// Only the rodio backend has devices we can enumerate, the others take a device name, path or command as is.
+#[cfg(not(feature = "rodio-backend"))]
+pub fn list_output_devices() -> Vec<String> {
+    vec![]
+}
+
 impl SpotiampSink {
-    pub fn new(
-        file: Option<String>,
-        format: AudioFormat,
-        visualizer: Arc<Mutex<Visualizer>>,
-        volume: Arc<AtomicU16>,
-    ) -> Self {
+    pub fn new(format: AudioFormat, state: SinkState) -> Self {
+        let output = state.output.lock().unwrap().clone();
         Self {
-            backend_delegate: audio_backend::find(None).unwrap()(file, format),
-            visualizer,
-            volume,
+            backend_delegate: Self::open_backend(&output, format),
+            format,
+            output,
+            running: false,
+            state,
             scratch: Vec::new(),
         }
     }
+
+    fn open_backend(output: &OutputSettings, format: AudioFormat) -> Box<dyn Sink> {
+        let default = audio_backend::BACKENDS
+            .first()
+            .expect("at least one audio backend");
+        let &(name, builder) = match &output.backend {
+            Some(backend) => audio_backend::BACKENDS
+                .iter()
+                .find(|(name, _)| *name == backend.as_str())
+                .unwrap_or_else(|| {
+                    log::warn!(
+                        "Audio backend '{backend}' is not available, using '{}'",
+                        default.0
+                    );
+                    default
+                }),
+            None => default,
+        };
+
+        let device = match name {
+            // The rodio backend panics on devices it can't find, so fall back to the default device if it has gone away
+            "rodio" => output.device.clone().filter(|device| {
+                let available = list_output_devices().contains(device);
+                if !available {
+                    log::warn!(
+                        "Output device '{device}' is not available, using the default device"
+                    );
+                }
+                available
+            }),
+            // ...and the subprocess backend exits the app if it has no command to run
+            "subprocess" if output.device.is_none() => {
+                log::error!(
+                    "The subprocess backend needs a command, using '{}'",
+                    default.0
+                );
+                return (default.1)(None, format);
+            }
+            _ => output.device.clone(),
+        };
+
+        log::info!("Opening audio backend '{name}' (device: {device:?})");
+        builder(device, format)
+    }
+
+    /// Swaps the backend for one using the selected backend and device if they have changed.
+    fn apply_output_selection(&mut self) -> SinkResult<()> {
+        let output = self.state.output.lock().unwrap().clone();
+        if output == self.output {
+            return Ok(());
+        }
+
+        if self.running {
+            self.backend_delegate.stop()?;
+        }
+        self.backend_delegate = Self::open_backend(&output, self.format);
+        self.output = output;
+        if self.running {
+            self.backend_delegate.start()?;
+        }
+        Ok(())
+    }
+
+    /// Constant-power pan law, normalised so that the centre position leaves both channels untouched
+    /// and the channel we are panning towards is never boosted above unity.
+    fn apply_balance(&self, samples: &mut [f64]) {
+        let balance = self
+            .state
+            .balance
+            .load(std::sync::atomic::Ordering::Relaxed);
+        if balance == 0 {
+            return;
+        }
+
+        let angle = (balance.clamp(-100, 100) as f64 + 100.0) / 200.0 * FRAC_PI_2;
+        let left_gain = (angle.cos() * 2f64.sqrt()).min(1.0);
+        let right_gain = (angle.sin() * 2f64.sqrt()).min(1.0);
+        for frame in samples.chunks_exact_mut(NUM_CHANNELS as usize) {
+            frame[0] *= left_gain;
+            frame[1] *= right_gain;
+        }
+    }
+
+    fn write_samples(
+        &mut self,
+        mut samples: Vec<f64>,
+        converter: &mut Converter,
+    ) -> SinkResult<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+
+        self.state.equalizer.lock().unwrap().process(&mut samples);
+        self.apply_balance(&mut samples);
+        self.state.fader.lock().unwrap().apply(&mut samples);
+
+        if samples.len() > self.scratch.len() {
+            self.scratch.resize(samples.len().next_power_of_two(), 0.0);
+        }
+        let volume = 100.0 / self.state.volume.load(std::sync::atomic::Ordering::Relaxed) as f32;
+        if volume > 0.0 {
+            let mut visualizer = self.state.visualizer.lock().unwrap();
+            for (idx, s) in samples.iter().enumerate() {
+                self.scratch[idx] = *s as f32 * volume;
+            }
+            visualizer.push_samples(&self.scratch[..samples.len()]);
+        }
+
+        self.backend_delegate
+            .write(AudioPacket::Samples(samples), converter)
+    }
 }
 
 impl Sink for SpotiampSink {
+    /// Fades back in after a pause, whoever resumed playing (us or a Spotify Connect client).
     fn start(&mut self) -> SinkResult<()> {
-        self.backend_delegate.start()
+        self.state.fader.lock().unwrap().fade_in();
+        self.apply_output_selection()?;
+        self.backend_delegate.start()?;
+        self.running = true;
+        Ok(())
     }
 
     fn stop(&mut self) -> SinkResult<()> {
+        // Play out a tail held back for crossfading if there turned out to be no next track
+        let tail = self.state.crossfader.lock().unwrap().take_tail();
+        if self.running {
+            self.write_samples(tail, &mut Converter::new(None))?;
+        }
+        self.state.time_stretcher.lock().unwrap().reset();
+        self.running = false;
         self.backend_delegate.stop()
     }
 
     fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
-        if let Ok(samples) = packet.samples() {
-            if samples.len() > self.scratch.len() {
-                self.scratch.resize(samples.len().next_power_of_two(), 0.0);
-            }
-            let volume = 100.0 / self.volume.load(std::sync::atomic::Ordering::Relaxed) as f32;
-            if volume > 0.0 {
-                let mut visualizer = self.visualizer.lock().unwrap();
-                for (idx, s) in samples.iter().enumerate() {
-                    self.scratch[idx] = *s as f32 * volume;
-                }
-                visualizer.push_samples(&self.scratch[..samples.len()]);
+        self.apply_output_selection()?;
+
+        match packet {
+            AudioPacket::Samples(samples) => {
+                self.state.recorder.lock().unwrap().write(
+                    &samples,
+                    self.state.volume.load(std::sync::atomic::Ordering::Relaxed) as f64 / 100.0,
+                );
+                let samples = self.state.crossfader.lock().unwrap().process(samples);
+                let samples = self.state.time_stretcher.lock().unwrap().process(samples);
+                self.write_samples(samples, converter)
             }
+            packet => self.backend_delegate.write(packet, converter),
         }
-
-        self.backend_delegate.write(packet, converter)
     }
 }
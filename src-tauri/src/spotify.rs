@@ -1,49 +1,86 @@
 use std::{
-    sync::{Arc, Mutex, atomic::AtomicU16},
-    time::Duration,
+    path::PathBuf,
+    pin::pin,
+    sync::{Arc, Mutex, Weak, atomic::AtomicU16},
+    time::{Duration, Instant},
 };
 
 use crate::{
-    oauth::{OAuthError, OAuthFlow},
-    settings::Settings,
-    sink::SpotiampSink,
-    visualizer::Visualizer,
+    connect::{self, ConnectMixer},
+    crossfade::Crossfader,
+    metadata_cache::MetadataCache,
+    oauth::{OAuthError, OAuthFlow, OAuthToken},
+    player_window::{CoverSize, EpisodeMetadata, TrackMetadata},
+    recorder::RecordingError,
+    settings::{
+        ArtistExpansion, LoginMethod, NormalisationMethodSetting, NormalisationTypeSetting,
+        OutputSettings, RepeatMode, ResumeSettings, Settings,
+    },
+    sink::{Fader, SinkState, SpotiampSink},
+};
+use futures_util::{
+    FutureExt, StreamExt,
+    future::{BoxFuture, Either, select},
 };
 use librespot::{
+    connect::Spirc,
     core::{
-        Error, SpotifyUri, authentication::Credentials, cache::Cache, config::SessionConfig,
+        Error, FileId, SpotifyUri,
+        authentication::Credentials,
+        cache::Cache,
+        config::{DeviceType, SessionConfig},
         session::Session,
     },
-    metadata::{Album, Metadata, Playlist, Track},
+    discovery::{Discovery, Error as DiscoveryError},
+    metadata::{
+        Album, Artist, Episode, Metadata, Playlist, Show, Track,
+        audio::{AudioFileFormat, AudioFiles},
+    },
     playback::{
         config::{AudioFormat, Bitrate, NormalisationMethod, NormalisationType, PlayerConfig},
         dither::{TriangularDitherer, mk_ditherer},
         mixer::VolumeGetter,
-        player::{Player, PlayerEventChannel, duration_to_coefficient},
+        player::{Player, PlayerEvent, PlayerEventChannel, duration_to_coefficient},
     },
 };
-use oauth2::TokenResponse;
+use rand::seq::SliceRandom;
 use tauri::AppHandle;
+use tauri_plugin_dialog::{DialogExt, MessageDialogButtons};
 use thiserror::Error;
+use tokio::sync::{
+    Notify,
+    mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    oneshot,
+};
 
 use crate::settings::get_config_dir;
-pub type SharedPlayer = Arc<tokio::sync::Mutex<SpotifyPlayer>>;
+pub type SharedPlayer = Arc<SpotifyPlayer>;
+/// Logging in may mean creating an account first, so be generous
+const OAUTH_LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Picking a device only takes a moment, if nobody has by then there is probably no Spotify client around
+const ZEROCONF_LOGIN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Playback only needs `streaming`, the rest is for reading the user's library through the Web API
+const OAUTH_SCOPES: &[&str] = &[
+    "streaming",
+    "user-library-read",
+    "playlist-read-private",
+    "playlist-read-collaborative",
+];
+
 pub struct SpotifySession {
-    inner: Session,
-    cache: Cache,
+    /// Replaced when reconnecting, as a librespot session can't be used again once it has been invalidated
+    inner: Mutex<Session>,
+    /// Holds the credentials of the active account (and the audio files of all of them)
+    cache: Mutex<Cache>,
 }
 
 impl Default for SpotifySession {
     fn default() -> Self {
-        let cache = get_config_dir()
-            .and_then(|config_dir| {
-                Cache::new(Some(config_dir.clone()), None, Some(config_dir), None).ok()
-            })
-            .expect("a cache to be created");
+        let cache = Self::account_cache(Settings::current().accounts.active.as_deref());
         let session = Session::new(SessionConfig::default(), Some(cache.clone()));
         Self {
-            inner: session,
-            cache,
+            inner: Mutex::new(session),
+            cache: Mutex::new(cache),
         }
     }
 }
@@ -51,29 +88,290 @@ impl Default for SpotifySession {
 impl SpotifySession {
     pub async fn login(&self, app: &AppHandle) -> Result<(), SessionError> {
         log::debug!("Getting credentials");
-        let credentials = match self.cache.credentials() {
-            Some(credentials) => credentials,
+        match self.cache().credentials() {
+            Some(credentials) => {
+                let active = Settings::current().accounts.active.clone();
+                self.connect(active.as_deref(), credentials).await?;
+            }
             None => {
-                log::debug!("No credentials in cache, starting OAuth flow...");
-                Self::get_credentials_from_oauth(app).await?
+                log::debug!("No credentials in cache");
+                let login = Self::get_new_login(app).await?;
+                self.connect_new(login).await?;
             }
-        };
+        }
+        log::debug!("Success! Saving the credentials for next time");
+        Ok(())
+    }
 
-        self.inner
+    /// Asks the user to log in, `LoginCancelled` if they would rather not.
+    pub async fn get_new_login(app: &AppHandle) -> Result<NewLogin, SessionError> {
+        match Self::login_method() {
+            LoginMethod::OAuth => {
+                log::debug!("Starting OAuth flow...");
+                Self::get_credentials_from_oauth(app).await
+            }
+            LoginMethod::Zeroconf => {
+                log::debug!("Waiting for credentials over zeroconf...");
+                Self::get_credentials_from_zeroconf(app).await
+            }
+        }
+    }
+
+    pub fn get(&self) -> Session {
+        self.inner.lock().unwrap().clone()
+    }
+
+    fn cache(&self) -> Cache {
+        self.cache.lock().unwrap().clone()
+    }
+
+    /// The connection was lost, e.g. while the computer was asleep.
+    pub fn is_invalid(&self) -> bool {
+        self.inner.lock().unwrap().is_invalid()
+    }
+
+    /// Connects a new session with the cached credentials and replaces the current one with it.
+    pub async fn reconnect(&self) -> Result<(), SessionError> {
+        let credentials = self
+            .cache()
+            .credentials()
+            .ok_or(SessionError::NoCachedCredentials)?;
+        let active = Settings::current().accounts.active.clone();
+        self.connect(active.as_deref(), credentials).await?;
+        Ok(())
+    }
+
+    /// Swaps in a session that isn't connected yet, for Spotify Connect which connects it itself.
+    pub fn renew(&self) -> Result<Credentials, SessionError> {
+        let credentials = self
+            .cache()
+            .credentials()
+            .ok_or(SessionError::NoCachedCredentials)?;
+        *self.inner.lock().unwrap() = Session::new(SessionConfig::default(), Some(self.cache()));
+        Ok(credentials)
+    }
+
+    /// Connects an account, `None` for one that just logged in and hasn't got a credentials directory yet.
+    /// Only once connected does it become the active account, until then the current one stays.
+    async fn connect(
+        &self,
+        account: Option<&str>,
+        credentials: Credentials,
+    ) -> Result<String, SessionError> {
+        let cache = Self::account_cache(account);
+        let session = Session::new(SessionConfig::default(), Some(cache.clone()));
+        session
             .connect(credentials, true)
             .await
             .map_err(|e| SessionError::ConnectError { e })?;
-        log::debug!("Success! Using credentials from OAuth-flow and saving them for next time");
+        let username = session.username();
+        if account.is_none() {
+            Self::move_credentials_to_account(&cache, &username);
+        }
+        self.remember_account(&username);
+        *self.inner.lock().unwrap() = session;
+        Ok(username)
+    }
+
+    /// Connects an account that has just logged in, and keeps its OAuth token with its credentials.
+    async fn connect_new(&self, login: NewLogin) -> Result<(), SessionError> {
+        let username = self.connect(None, login.credentials).await?;
+        if let Some(token) = login.oauth_token
+            && let Some(credentials_dir) = Self::credentials_dir(Some(&username))
+        {
+            token.save(&credentials_dir);
+        }
+        Ok(())
+    }
+
+    /// Gives an account its own credentials directory the first time it logs in.
+    fn move_credentials_to_account(cache: &Cache, username: &str) {
+        if let Some(credentials) = cache.credentials() {
+            Self::account_cache(Some(username)).save_credentials(&credentials);
+            Self::remove_credentials(None);
+        }
+    }
+
+    /// Makes the account the active one, and makes sure it is in the list.
+    fn remember_account(&self, username: &str) {
+        *self.cache.lock().unwrap() = Self::account_cache(Some(username));
+        let mut settings = Settings::current_mut();
+        let accounts = &mut settings.accounts;
+        accounts.active = Some(username.to_string());
+        if !accounts.names.iter().any(|name| name == username) {
+            accounts.names.push(username.to_string());
+        }
+    }
+
+    /// Uses the credentials of another account that has logged in before.
+    pub async fn switch_account(&self, name: &str) -> Result<(), SessionError> {
+        if !Settings::current()
+            .accounts
+            .names
+            .iter()
+            .any(|known_name| known_name == name)
+        {
+            return Err(SessionError::UnknownAccount(name.to_string()));
+        }
+
+        let credentials = Self::account_cache(Some(name))
+            .credentials()
+            .ok_or(SessionError::NoCachedCredentials)?;
+        self.connect(Some(name), credentials).await?;
         Ok(())
     }
 
-    async fn get_credentials_from_oauth(app: &AppHandle) -> Result<Credentials, SessionError> {
-        let oauth_flow = OAuthFlow::new(
+    /// Connects an account that has just logged in, keeping the current one to switch back to.
+    pub async fn add_account(&self, login: NewLogin) -> Result<(), SessionError> {
+        self.connect_new(login).await
+    }
+
+    /// Forgets the active account's credentials and shows the login window. If that is cancelled,
+    /// another saved account is used (if there is one).
+    pub async fn logout(&self, app: &AppHandle) -> Result<(), SessionError> {
+        let remaining_account = {
+            let mut settings = Settings::current_mut();
+            let accounts = &mut settings.accounts;
+            if let Some(active) = accounts.active.take() {
+                log::info!("Logging out {active}");
+                Self::remove_credentials(Some(&active));
+                accounts.names.retain(|name| *name != active);
+            }
+            // Legacy credentials from before accounts had a directory of their own
+            Self::remove_credentials(None);
+            accounts.names.first().cloned()
+        };
+        *self.cache.lock().unwrap() = Self::account_cache(None);
+        self.get().shutdown();
+
+        let login = match Self::get_new_login(app).await {
+            Err(SessionError::LoginCancelled) => match remaining_account {
+                Some(name) => {
+                    log::info!("Login cancelled, switching to {name}");
+                    return self.switch_account(&name).await;
+                }
+                None => return Err(SessionError::LoginCancelled),
+            },
+            login => login?,
+        };
+        self.connect_new(login).await
+    }
+
+    fn credentials_dir(account: Option<&str>) -> Option<PathBuf> {
+        get_config_dir().map(|config_dir| match account {
+            Some(name) => config_dir.join("accounts").join(name),
+            None => config_dir,
+        })
+    }
+
+    fn account_cache(account: Option<&str>) -> Cache {
+        get_config_dir()
+            .and_then(|config_dir| {
+                Cache::new(Self::credentials_dir(account), None, Some(config_dir), None).ok()
+            })
+            .expect("a cache to be created")
+    }
+
+    /// The OAuth token goes with them.
+    fn remove_credentials(account: Option<&str>) {
+        let Some(credentials_dir) = Self::credentials_dir(account) else {
+            return;
+        };
+        OAuthToken::remove(&credentials_dir);
+        let credentials_path = credentials_dir.join("credentials.json");
+        if let Err(e) = std::fs::remove_file(&credentials_path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            log::error!("Could not remove credentials {credentials_path:?} ({e:?})");
+        }
+    }
+
+    /// `--zeroconf-login` overrides the setting, for when the OAuth window can't be used to change it.
+    fn login_method() -> LoginMethod {
+        if std::env::args().any(|arg| arg == "--zeroconf-login") {
+            LoginMethod::Zeroconf
+        } else {
+            Settings::current().accounts.login_method
+        }
+    }
+
+    /// Shows up as a Spotify Connect device until a Spotify client on the network picks it, which hands over its credentials.
+    /// Falls back to OAuth if the user would rather log in in the browser, or if no client picks us in time.
+    async fn get_credentials_from_zeroconf(app: &AppHandle) -> Result<NewLogin, SessionError> {
+        let session_config = SessionConfig::default();
+        let device_name = Settings::current().connect.device_name.clone();
+        let mut discovery = Discovery::builder(session_config.device_id, session_config.client_id)
+            .name(device_name.clone())
+            .device_type(DeviceType::Computer)
+            .launch()
+            .map_err(|e| SessionError::DiscoveryFailed { e })?;
+
+        let (use_oauth_tx, use_oauth_rx) = oneshot::channel();
+        app.dialog()
+            .message(format!(
+                "Open Spotify on a phone or computer on this network and pick '{device_name}' from its devices to log in."
+            ))
+            .title("Login")
+            .buttons(MessageDialogButtons::OkCancelCustom(
+                "Log in in the browser".to_string(),
+                "Keep waiting".to_string(),
+            ))
+            .show(move |use_oauth| {
+                let _ = use_oauth_tx.send(use_oauth);
+            });
+        let use_oauth = async {
+            if !matches!(use_oauth_rx.await, Ok(true)) {
+                std::future::pending::<()>().await;
+            }
+        };
+
+        let discovered = tokio::time::timeout(
+            ZEROCONF_LOGIN_TIMEOUT,
+            select(pin!(discovery.next()), pin!(use_oauth)),
+        )
+        .await;
+        match discovered {
+            Ok(Either::Left((credentials, _))) => Ok(NewLogin {
+                credentials: credentials.ok_or(SessionError::DiscoveryEnded)?,
+                oauth_token: None,
+            }),
+            Ok(Either::Right(_)) => {
+                log::info!("Logging in with OAuth instead of zeroconf");
+                Self::get_credentials_from_oauth(app).await
+            }
+            Err(_) => {
+                log::info!(
+                    "No Spotify client picked us within {ZEROCONF_LOGIN_TIMEOUT:?}, logging in with OAuth instead"
+                );
+                Self::get_credentials_from_oauth(app).await
+            }
+        }
+    }
+
+    /// Also used to refresh the token for the Web API.
+    fn oauth_flow() -> Result<OAuthFlow, OAuthError> {
+        OAuthFlow::new(
             "https://accounts.spotify.com/authorize",
             "https://accounts.spotify.com/api/token",
             "65b708073fc0480ea92a077233ca87bd",
+            OAUTH_SCOPES,
         )
-        .map_err(|e| SessionError::OauthError { e })?;
+    }
+
+    /// An access token for the Web API of the active account, refreshed if it has expired.
+    pub async fn web_api_token() -> Result<String, OAuthError> {
+        let active = Settings::current().accounts.active.clone();
+        let credentials_dir = active
+            .as_deref()
+            .and_then(|name| Self::credentials_dir(Some(name)))
+            .ok_or(OAuthError::NoRefreshToken)?;
+        Self::oauth_flow()?.access_token(&credentials_dir).await
+    }
+
+    async fn get_credentials_from_oauth(app: &AppHandle) -> Result<NewLogin, SessionError> {
+        let oauth_flow = Self::oauth_flow()
+            .map_err(|e| SessionError::OauthError { e })?
+            .with_timeout(OAUTH_LOGIN_TIMEOUT);
 
         let auth_url = oauth_flow.get_auth_url();
         log::debug!("Opening URL: {auth_url}");
@@ -92,62 +390,456 @@ impl SpotifySession {
         .map_err(|e| SessionError::OpenURLFailed { url: auth_url, e })?;
 
         let token_received = Arc::new(Mutex::new(false));
+        let cancelled = Arc::new(Notify::new());
         window.on_window_event({
             let token_received = token_received.clone();
+            let cancelled = cancelled.clone();
             move |e| {
                 if let tauri::WindowEvent::CloseRequested { .. } = &e
                     && !*token_received.lock().unwrap()
                 {
-                    log::info!("No token received when closing login window. Exiting.");
-                    std::process::exit(0);
+                    log::info!("No token received when closing login window");
+                    cancelled.notify_one();
                 }
             }
         });
 
-        let token = oauth_flow
-            .start()
-            .await
-            .map_err(|e| SessionError::TokenExchangeFailure { e })?;
+        // Dropping the flow when cancelled shuts its redirect server down
+        let token = match select(pin!(oauth_flow.start()), pin!(cancelled.notified())).await {
+            Either::Left((token, _)) => token,
+            Either::Right(_) => return Err(SessionError::LoginCancelled),
+        };
         *token_received.lock().unwrap() = true;
         let _ = window.close();
+        let token = token.map_err(|e| SessionError::TokenExchangeFailure { e })?;
+
+        Ok(NewLogin {
+            credentials: Credentials::with_access_token(&token.access_token),
+            oauth_token: Some(token),
+        })
+    }
+}
+
+/// What a login hands over, before we know which account it is.
+pub struct NewLogin {
+    credentials: Credentials,
+    /// Only when logging in with OAuth, zeroconf doesn't give us a Web API token.
+    oauth_token: Option<OAuthToken>,
+}
+
+/// What the player is currently doing, tracked from its events so that a rebuilt player can pick up
+/// where the old one left off.
+#[derive(Debug, Default)]
+struct PlaybackState {
+    uri: Option<SpotifyUri>,
+    position_ms: u32,
+    position_updated_at: Option<Instant>,
+    playing: bool,
+}
 
-        Ok(Credentials::with_access_token(
-            token.access_token().secret(),
-        ))
+impl PlaybackState {
+    fn update(&mut self, player_event: &PlayerEvent) {
+        match player_event {
+            PlayerEvent::Playing {
+                track_id,
+                position_ms,
+                ..
+            } => self.set(track_id, *position_ms, true),
+            PlayerEvent::Paused {
+                track_id,
+                position_ms,
+                ..
+            } => self.set(track_id, *position_ms, false),
+            PlayerEvent::PositionCorrection {
+                track_id,
+                position_ms,
+                ..
+            }
+            | PlayerEvent::PositionChanged {
+                track_id,
+                position_ms,
+                ..
+            }
+            | PlayerEvent::Seeked {
+                track_id,
+                position_ms,
+                ..
+            } => self.set(track_id, *position_ms, self.playing),
+            PlayerEvent::Stopped { .. } => *self = Self::default(),
+            PlayerEvent::EndOfTrack { track_id, .. } => self.set(track_id, 0, false),
+            _ => {}
+        }
     }
+
+    fn set(&mut self, uri: &SpotifyUri, position_ms: u32, playing: bool) {
+        self.uri = Some(uri.clone());
+        self.position_ms = position_ms;
+        self.position_updated_at = Some(Instant::now());
+        self.playing = playing;
+    }
+
+    fn current_position_ms(&self) -> u32 {
+        match self.position_updated_at {
+            Some(updated_at) if self.playing => {
+                self.position_ms + updated_at.elapsed().as_millis() as u32
+            }
+            _ => self.position_ms,
+        }
+    }
+}
+
+/// Where to carry on in a rebuilt player.
+struct ResumePoint {
+    uri: SpotifyUri,
+    position_ms: u32,
+    playing: bool,
+}
+
+/// Where the play queue is at, sent to the queue's subscribers whenever it moves.
+#[derive(Debug, Clone)]
+pub struct QueuePosition {
+    pub index: usize,
+    pub uri: String,
 }
 
+/// The playlist as the player plays it. It lives here rather than in the playlist window so that playback
+/// carries on to the next track even when the webview is busy or reloading.
+#[derive(Default)]
+pub struct PlayQueue {
+    uris: Vec<String>,
+    current: Option<usize>,
+    /// Indices into `uris` in the order they are played, shuffled or not
+    order: Vec<usize>,
+    shuffle: bool,
+    repeat: RepeatMode,
+    unavailable_in_a_row: usize,
+    subscribers: Vec<UnboundedSender<QueuePosition>>,
+}
+
+impl PlayQueue {
+    pub fn new(uris: Vec<String>, shuffle: bool, repeat: RepeatMode) -> Self {
+        let mut queue = Self {
+            current: (!uris.is_empty()).then_some(0),
+            uris,
+            shuffle,
+            repeat,
+            ..Default::default()
+        };
+        queue.reorder();
+        queue
+    }
+
+    /// Replaces the entries, staying on the current track if it is still there.
+    pub fn set_uris(&mut self, uris: Vec<String>) {
+        self.current = self
+            .current
+            .and_then(|current| Self::find(&uris, &self.uris[current], current));
+        self.uris = uris;
+        self.reorder();
+    }
+
+    /// Moves to where a previous session left off without notifying anyone, returns false if the track isn't in the queue anymore.
+    pub fn restore(&mut self, uri: &str, index: usize) -> bool {
+        self.current = Self::find(&self.uris, uri, index).or(self.current);
+        self.reorder();
+        self.current_uri() == Some(uri)
+    }
+
+    pub fn current_index(&self) -> Option<usize> {
+        self.current
+    }
+
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        self.shuffle = shuffle;
+        self.reorder();
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.repeat = repeat;
+    }
+
+    pub fn current_uri(&self) -> Option<&str> {
+        self.current.map(|current| self.uris[current].as_str())
+    }
+
+    pub fn jump_to(&mut self, index: usize) -> Option<&str> {
+        if index >= self.uris.len() {
+            return None;
+        }
+        self.move_to(index);
+        self.current_uri()
+    }
+
+    /// Moves on to the next track. Repeating a single track only applies when a track ends by itself, not when skipping.
+    pub fn next(&mut self, track_ended: bool) -> Option<&str> {
+        let next = self.peek_next_index(track_ended)?;
+        self.move_to(next);
+        self.current_uri()
+    }
+
+    /// The track that will play when the current one ends.
+    pub fn peek_next(&self) -> Option<&str> {
+        self.peek_next_index(true)
+            .map(|next| self.uris[next].as_str())
+    }
+
+    /// Moves back a track, staying on the first one unless repeating the whole queue.
+    pub fn previous(&mut self) -> Option<&str> {
+        let previous = match self.order_position() {
+            Some(0) if self.repeat == RepeatMode::All => *self.order.last()?,
+            Some(position) => self.order[position.saturating_sub(1)],
+            None => *self.order.first()?,
+        };
+        self.move_to(previous);
+        self.current_uri()
+    }
+
+    /// Keeps track of tracks that could not be played, returns true when all of them have failed and it's time to give up.
+    pub fn track_unavailable(&mut self) -> bool {
+        self.unavailable_in_a_row += 1;
+        self.unavailable_in_a_row >= self.uris.len()
+    }
+
+    pub fn track_playing(&mut self) {
+        self.unavailable_in_a_row = 0;
+    }
+
+    pub fn subscribe(&mut self) -> UnboundedReceiver<QueuePosition> {
+        let (tx, rx) = unbounded_channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// The same track can be in there more than once, pick the one closest to where we were.
+    fn find(uris: &[String], uri: &str, near: usize) -> Option<usize> {
+        uris.iter()
+            .enumerate()
+            .filter(|(_, candidate)| *candidate == uri)
+            .min_by_key(|(index, _)| index.abs_diff(near))
+            .map(|(index, _)| index)
+    }
+
+    fn peek_next_index(&self, track_ended: bool) -> Option<usize> {
+        if track_ended && self.repeat == RepeatMode::One && self.current.is_some() {
+            return self.current;
+        }
+        match self.order_position() {
+            Some(position) if position + 1 < self.order.len() => Some(self.order[position + 1]),
+            Some(_) if self.repeat != RepeatMode::Off => self.order.first().copied(),
+            Some(_) => None,
+            None => self.order.first().copied(),
+        }
+    }
+
+    fn order_position(&self) -> Option<usize> {
+        let current = self.current?;
+        self.order.iter().position(|index| *index == current)
+    }
+
+    fn move_to(&mut self, index: usize) {
+        self.current = Some(index);
+        let position = QueuePosition {
+            index,
+            uri: self.uris[index].clone(),
+        };
+        self.subscribers
+            .retain(|subscriber| subscriber.send(position.clone()).is_ok());
+    }
+
+    /// Lays out the play order, when shuffling the current track goes first so the rest of the queue is still ahead of us.
+    fn reorder(&mut self) {
+        self.order = (0..self.uris.len()).collect();
+        if self.shuffle {
+            self.order.shuffle(&mut rand::rng());
+            if let Some(current) = self.current {
+                self.order.retain(|index| *index != current);
+                self.order.insert(0, current);
+            }
+        }
+    }
+}
+
+/// Spotify Connect, and whether a Connect client or we decided what is playing.
+#[derive(Default)]
+struct ConnectState {
+    spirc: Option<Spirc>,
+    /// Loads we started whose `Loading` event hasn't come by yet, any other load came from a Connect client
+    local_loads: usize,
+    remote_active: bool,
+}
+
+impl ConnectState {
+    fn loading_locally(&mut self) {
+        self.local_loads += 1;
+    }
+
+    /// Loads that the old player hadn't got to yet never will, they mustn't be mistaken for the next remote ones.
+    fn player_replaced(&mut self) {
+        self.local_loads = 0;
+    }
+
+    fn follow(&mut self, player_event: &PlayerEvent) {
+        if let PlayerEvent::Loading { .. } = player_event {
+            if self.local_loads > 0 {
+                self.local_loads -= 1;
+                self.remote_active = false;
+            } else if self.spirc.is_some() {
+                self.remote_active = true;
+            }
+        }
+    }
+
+    fn remote_spirc(&self) -> Option<&Spirc> {
+        self.spirc.as_ref().filter(|_| self.remote_active)
+    }
+}
+
+type PlayerEventSubscribers = Arc<Mutex<Vec<UnboundedSender<PlayerEvent>>>>;
+/// Where track metadata comes from, Spotify except in tests
+type TrackLookup =
+    Arc<dyn Fn(Session, SpotifyUri) -> BoxFuture<'static, Result<Track, PlayError>> + Send + Sync>;
+
+/// Every part has its own lock (if it needs one at all), so e.g. a slow metadata lookup doesn't hold up pausing.
 pub struct SpotifyPlayer {
-    player: Arc<Player>,
+    /// Only replaced when rebuilding the player
+    player: Mutex<Arc<Player>>,
     pub session: SpotifySession,
-    volume: Arc<AtomicU16>,
+    sink_state: SinkState,
 
-    visualizer: Arc<Mutex<Visualizer>>,
+    playback: Arc<Mutex<PlaybackState>>,
+    event_subscribers: PlayerEventSubscribers,
+    queue: Arc<Mutex<PlayQueue>>,
+    connect: Arc<Mutex<ConnectState>>,
+    track_lookup: TrackLookup,
 }
 
 impl SpotifyPlayer {
     #[allow(clippy::new_without_default)]
     pub fn new(session: SpotifySession) -> Self {
-        let player_config = PlayerConfig {
+        let sink_state = SinkState::new(&Settings::current());
+        let queue = {
+            let playlist = &Settings::current().playlist;
+            PlayQueue::new(playlist.uris.clone(), playlist.shuffle, playlist.repeat)
+        };
+        let track_lookup: TrackLookup = Arc::new(|session: Session, uri: SpotifyUri| {
+            async move { get_track(&session, uri).await }.boxed()
+        });
+        Self::with_parts(session, sink_state, queue, track_lookup)
+    }
+
+    fn with_parts(
+        session: SpotifySession,
+        sink_state: SinkState,
+        queue: PlayQueue,
+        track_lookup: TrackLookup,
+    ) -> Self {
+        let playback = Arc::new(Mutex::new(PlaybackState::default()));
+        let event_subscribers = PlayerEventSubscribers::default();
+        let queue = Arc::new(Mutex::new(queue));
+        let connect = Arc::new(Mutex::new(ConnectState::default()));
+        let player = Self::build_player(&session, &sink_state);
+        Self::forward_player_events(
+            &player,
+            sink_state.clone(),
+            playback.clone(),
+            event_subscribers.clone(),
+            queue.clone(),
+            connect.clone(),
+        );
+
+        Self {
+            player: Mutex::new(player),
+            session,
+            sink_state,
+            playback,
+            event_subscribers,
+            queue,
+            connect,
+            track_lookup,
+        }
+    }
+
+    fn player_config() -> PlayerConfig {
+        let normalisation = Settings::current().normalisation.clone();
+        PlayerConfig {
             // Emit a position update every second so the UI can re-sync its
             // playback clock instead of free-running and drifting from the
             // actual position (which left the seek bar short at end of track).
             position_update_interval: Some(Duration::from_secs(1)),
-            bitrate: Bitrate::Bitrate320,
+            bitrate: Self::bitrate(),
             gapless: true,
-            normalisation: false,
-            normalisation_type: NormalisationType::default(),
-            normalisation_method: NormalisationMethod::Dynamic,
-            normalisation_pregain_db: 0.0,
-            normalisation_threshold_dbfs: -2.0,
-            normalisation_attack_cf: duration_to_coefficient(Duration::from_millis(5)),
-            normalisation_release_cf: duration_to_coefficient(Duration::from_millis(100)),
-            normalisation_knee_db: 5.0,
+            normalisation: normalisation.enabled,
+            normalisation_type: match normalisation.normalisation_type {
+                NormalisationTypeSetting::Auto => NormalisationType::Auto,
+                NormalisationTypeSetting::Album => NormalisationType::Album,
+                NormalisationTypeSetting::Track => NormalisationType::Track,
+            },
+            normalisation_method: match normalisation.method {
+                NormalisationMethodSetting::Basic => NormalisationMethod::Basic,
+                NormalisationMethodSetting::Dynamic => NormalisationMethod::Dynamic,
+            },
+            normalisation_pregain_db: normalisation.pregain_db,
+            normalisation_threshold_dbfs: normalisation.threshold_dbfs,
+            normalisation_attack_cf: duration_to_coefficient(Duration::from_millis(
+                normalisation.attack_ms,
+            )),
+            normalisation_release_cf: duration_to_coefficient(Duration::from_millis(
+                normalisation.release_ms,
+            )),
+            normalisation_knee_db: normalisation.knee_db,
             local_file_directories: Vec::new(),
             passthrough: false,
             ditherer: Some(mk_ditherer::<TriangularDitherer>),
+        }
+    }
+
+    fn bitrate() -> Bitrate {
+        match Settings::current().player.bitrate {
+            96 => Bitrate::Bitrate96,
+            160 => Bitrate::Bitrate160,
+            _ => Bitrate::Bitrate320,
+        }
+    }
+
+    /// The file format librespot will pick for the configured bitrate, following the same preference order as its player.
+    /// It is inferred from the files on offer, the player doesn't report the one it actually decodes.
+    pub fn stream_format(files: &AudioFiles) -> Option<AudioFileFormat> {
+        use AudioFileFormat::*;
+        let formats = match Self::bitrate() {
+            Bitrate::Bitrate96 => [
+                OGG_VORBIS_96,
+                MP3_96,
+                OGG_VORBIS_160,
+                MP3_160,
+                MP3_256,
+                OGG_VORBIS_320,
+                MP3_320,
+            ],
+            Bitrate::Bitrate160 => [
+                OGG_VORBIS_160,
+                MP3_160,
+                OGG_VORBIS_96,
+                MP3_96,
+                MP3_256,
+                OGG_VORBIS_320,
+                MP3_320,
+            ],
+            Bitrate::Bitrate320 => [
+                OGG_VORBIS_320,
+                MP3_320,
+                MP3_256,
+                OGG_VORBIS_160,
+                MP3_160,
+                OGG_VORBIS_96,
+                MP3_96,
+            ],
         };
+        formats
+            .into_iter()
+            .find(|format| files.contains_key(format))
+    }
 
+    fn build_player(session: &SpotifySession, sink_state: &SinkState) -> Arc<Player> {
         struct SpotiampVolumeGetter {
             volume: Arc<AtomicU16>,
         }
@@ -158,80 +850,477 @@ impl SpotifyPlayer {
             }
         }
 
-        let volume = Arc::new(AtomicU16::new(Settings::current().player.volume));
-        let visualizer = Arc::new(Mutex::new(Visualizer::new()));
-        let player = Player::new(
-            player_config,
-            session.inner.clone(),
+        Player::new(
+            Self::player_config(),
+            session.get(),
             Box::new(SpotiampVolumeGetter {
-                volume: volume.clone(),
+                volume: sink_state.volume.clone(),
             }),
             {
-                let visualizer = visualizer.clone();
-                let volume = volume.clone();
+                let sink_state = sink_state.clone();
                 move || {
                     let audio_format = AudioFormat::F32;
-                    Box::new(SpotiampSink::new(None, audio_format, visualizer, volume))
+                    Box::new(SpotiampSink::new(audio_format, sink_state))
                 }
             },
+        )
+    }
+
+    /// Relays the events of a player to our subscribers. Subscribers keep their channel when the player is rebuilt.
+    fn forward_player_events(
+        player: &Arc<Player>,
+        sink_state: SinkState,
+        playback: Arc<Mutex<PlaybackState>>,
+        event_subscribers: PlayerEventSubscribers,
+        queue: Arc<Mutex<PlayQueue>>,
+        connect: Arc<Mutex<ConnectState>>,
+    ) {
+        let mut channel = player.get_player_event_channel();
+        let player = Arc::downgrade(player);
+        tauri::async_runtime::spawn(async move {
+            while let Some(player_event) = channel.recv().await {
+                playback.lock().unwrap().update(&player_event);
+                Self::remember_for_resume(&player_event, &playback, &queue);
+                Self::follow_for_crossfade(&player_event, &sink_state.crossfader);
+                Self::follow_for_fade(&player_event, &sink_state.fader);
+                connect.lock().unwrap().follow(&player_event);
+                Self::follow_queue(
+                    &player_event,
+                    &sink_state.crossfader,
+                    &queue,
+                    &connect,
+                    &player,
+                );
+                event_subscribers
+                    .lock()
+                    .unwrap()
+                    .retain(|subscriber| subscriber.send(player_event.clone()).is_ok());
+            }
+        });
+    }
+
+    /// Saves where we are so the next session can resume from here. Not on every position update,
+    /// that would rewrite the settings every second, the latest position is saved when exiting instead.
+    fn remember_for_resume(
+        player_event: &PlayerEvent,
+        playback: &Mutex<PlaybackState>,
+        queue: &Mutex<PlayQueue>,
+    ) {
+        let resume = match player_event {
+            PlayerEvent::Playing { .. }
+            | PlayerEvent::Paused { .. }
+            | PlayerEvent::Seeked { .. } => Self::resume_settings(playback, queue),
+            // Stopping rewinds, but the track is still the one to come back to
+            PlayerEvent::Stopped { .. } => ResumeSettings {
+                position_ms: 0,
+                playing: false,
+                ..Settings::current().resume.clone()
+            },
+            _ => return,
+        };
+        Settings::current_mut().resume = resume;
+    }
+
+    fn resume_settings(
+        playback: &Mutex<PlaybackState>,
+        queue: &Mutex<PlayQueue>,
+    ) -> ResumeSettings {
+        let index = queue.lock().unwrap().current_index();
+        let playback = playback.lock().unwrap();
+        ResumeSettings {
+            uri: playback.uri.as_ref().and_then(|uri| uri.to_uri().ok()),
+            index,
+            position_ms: playback.current_position_ms(),
+            playing: playback.playing,
+        }
+    }
+
+    /// Saves the current position for resuming, for when we are about to exit.
+    pub fn save_resume_position(&self) {
+        // Stopped has already saved what there is to resume
+        if self.playback.lock().unwrap().uri.is_none() {
+            return;
+        }
+        Settings::current_mut().resume = Self::resume_settings(&self.playback, &self.queue);
+    }
+
+    /// Keeps the crossfader's idea of the track position in sync.
+    fn follow_for_crossfade(player_event: &PlayerEvent, crossfader: &Mutex<Crossfader>) {
+        match player_event {
+            PlayerEvent::TrackChanged { audio_item } => {
+                crossfader
+                    .lock()
+                    .unwrap()
+                    .track_changed(audio_item.duration_ms);
+            }
+            PlayerEvent::Playing { position_ms, .. }
+            | PlayerEvent::PositionCorrection { position_ms, .. }
+            | PlayerEvent::PositionChanged { position_ms, .. }
+            | PlayerEvent::Seeked { position_ms, .. } => {
+                crossfader.lock().unwrap().sync_position(*position_ms);
+            }
+            _ => {}
+        }
+    }
+
+    /// Seeking fades out first, and only once the player has actually jumped do we fade back in.
+    /// Before that the player is still writing the audio from before the seek.
+    fn follow_for_fade(player_event: &PlayerEvent, fader: &Mutex<Fader>) {
+        if let PlayerEvent::Seeked { .. } = player_event {
+            fader.lock().unwrap().micro_fade_in();
+        }
+    }
+
+    /// Moves the queue along when a track ends, or can't be played, and stops at the end of it.
+    /// The next track is preloaded ahead of time so it follows the current one without a gap
+    /// (and so there is something to crossfade into). A Spotify Connect client brings its own queue.
+    fn follow_queue(
+        player_event: &PlayerEvent,
+        crossfader: &Mutex<Crossfader>,
+        queue: &Mutex<PlayQueue>,
+        connect: &Mutex<ConnectState>,
+        player: &Weak<Player>,
+    ) {
+        if connect.lock().unwrap().remote_active {
+            return;
+        }
+        let next_uri = match player_event {
+            PlayerEvent::Playing { .. } => {
+                queue.lock().unwrap().track_playing();
+                return;
+            }
+            PlayerEvent::TimeToPreloadNextTrack { .. } => {
+                let next_uri = queue
+                    .lock()
+                    .unwrap()
+                    .peek_next()
+                    .and_then(|next_uri| SpotifyUri::from_uri(next_uri).ok());
+                if let Some(next_uri) = next_uri
+                    && let Some(player) = player.upgrade()
+                {
+                    log::debug!("Preloading {next_uri:?}");
+                    player.preload(next_uri);
+                }
+                return;
+            }
+            PlayerEvent::EndOfTrack { .. } => queue.lock().unwrap().next(true).map(str::to_string),
+            PlayerEvent::Unavailable { track_id, .. } => {
+                log::warn!("{track_id:?} is unavailable, skipping it");
+                let mut queue = queue.lock().unwrap();
+                if queue.track_unavailable() {
+                    log::warn!("Nothing in the queue can be played, stopping");
+                    None
+                } else {
+                    queue.next(false).map(str::to_string)
+                }
+            }
+            _ => return,
+        };
+
+        let Some(player) = player.upgrade() else {
+            return;
+        };
+        match next_uri.and_then(|next_uri| SpotifyUri::from_uri(&next_uri).ok()) {
+            Some(next_uri) => {
+                crossfader.lock().unwrap().begin_mix();
+                connect.lock().unwrap().loading_locally();
+                player.load(next_uri, true, 0);
+            }
+            None => player.stop(),
+        }
+    }
+
+    /// Replaces the librespot player with one using the current settings, resuming the loaded track where it was.
+    /// Spotify Connect holds on to the player it was started with, so it is restarted along with it.
+    pub async fn rebuild_player(&self) -> Result<(), SessionError> {
+        let resume_point = self.resume_point();
+        if self.connect.lock().unwrap().spirc.is_some() {
+            return self.restart_connect(resume_point).await;
+        }
+        self.replace_player(resume_point);
+        Ok(())
+    }
+
+    fn resume_point(&self) -> Option<ResumePoint> {
+        let playback = self.playback.lock().unwrap();
+        playback.uri.clone().map(|uri| ResumePoint {
+            uri,
+            position_ms: playback.current_position_ms(),
+            playing: playback.playing,
+        })
+    }
+
+    fn replace_player(&self, resume_point: Option<ResumePoint>) {
+        let player = Self::build_player(&self.session, &self.sink_state);
+        Self::forward_player_events(
+            &player,
+            self.sink_state.clone(),
+            self.playback.clone(),
+            self.event_subscribers.clone(),
+            self.queue.clone(),
+            self.connect.clone(),
         );
+        *self.player.lock().unwrap() = player;
+        self.connect.lock().unwrap().player_replaced();
+        self.resume_at(resume_point);
+    }
 
-        Self {
-            player,
-            session,
-            volume,
-            visualizer,
+    fn resume_at(&self, resume_point: Option<ResumePoint>) {
+        if let Some(ResumePoint {
+            uri,
+            position_ms,
+            playing,
+        }) = resume_point
+        {
+            log::debug!("Resuming {uri:?} at {position_ms}ms");
+            self.load(uri, playing, position_ms);
+        }
+    }
+
+    fn player(&self) -> Arc<Player> {
+        self.player.lock().unwrap().clone()
+    }
+
+    fn load(&self, uri: SpotifyUri, start_playing: bool, position_ms: u32) {
+        self.connect.lock().unwrap().loading_locally();
+        self.player().load(uri, start_playing, position_ms);
+    }
+
+    /// Hands a playback command to Spotify Connect if a Connect client is in charge, so it sees what happened.
+    fn with_remote_spirc(&self, command: impl FnOnce(&Spirc) -> Result<(), Error>) -> bool {
+        let connect = self.connect.lock().unwrap();
+        let Some(spirc) = connect.remote_spirc() else {
+            return false;
+        };
+        if let Err(e) = command(spirc) {
+            log::warn!("Spotify Connect command failed ({e:?})");
+        }
+        true
+    }
+
+    /// Shows up as a Spotify Connect device, dropping whatever was playing.
+    pub async fn start_connect(&self) -> Result<(), SessionError> {
+        self.stop_connect();
+        self.stop_for_session_change().await;
+        self.restart_connect(None).await
+    }
+
+    /// Connect connects a session of its own, so the player is rebuilt on a new one. Playback carries on
+    /// from the resume point once the session is connected.
+    async fn restart_connect(&self, resume_point: Option<ResumePoint>) -> Result<(), SessionError> {
+        self.stop_connect();
+        let credentials = self.session.renew()?;
+        self.replace_player(None);
+
+        let mixer = ConnectMixer::new(self.sink_state.volume.clone());
+        let config = connect::connect_config(&Settings::current().connect, &mixer);
+        log::info!("Starting Spotify Connect as '{}'", config.name);
+        let (spirc, spirc_task) = Spirc::new(
+            config,
+            self.session.get(),
+            credentials,
+            self.player(),
+            Arc::new(mixer),
+        )
+        .await
+        .map_err(|e| SessionError::ConnectError { e })?;
+        tauri::async_runtime::spawn(spirc_task);
+        self.connect.lock().unwrap().spirc = Some(spirc);
+        self.resume_at(resume_point);
+        Ok(())
+    }
+
+    pub fn stop_connect(&self) {
+        let mut connect = self.connect.lock().unwrap();
+        connect.remote_active = false;
+        if let Some(spirc) = connect.spirc.take() {
+            log::info!("Stopping Spotify Connect");
+            if let Err(e) = spirc.shutdown() {
+                log::warn!("Could not shut Spotify Connect down ({e:?})");
+            }
         }
     }
 
     pub async fn load_track(&self, uri: &str) -> Result<(), PlayError> {
         let uri = SpotifyUri::from_uri(uri).map_err(|e| PlayError::MetadataError { e })?;
-        self.player.load(uri, true, 0);
+        // Fade in from the end of the previous track if we held it back
+        self.sink_state.crossfader.lock().unwrap().begin_mix();
+        self.load(uri, true, 0);
         Ok(())
     }
 
-    pub fn play(&mut self) {
+    /// Resumes playback, or starts playing the current queue entry if nothing is loaded.
+    pub fn play(&self) {
         log::debug!("Play!");
-        self.player.play();
+        if self.with_remote_spirc(Spirc::play) {
+            return;
+        }
+        if self.playback.lock().unwrap().uri.is_some() {
+            self.player().play();
+        } else if let Some(uri) = self.queue.lock().unwrap().current_uri() {
+            self.load_queued(uri);
+        }
+    }
+
+    /// Picks up where the last session left off, with the track loaded but paused.
+    pub fn resume(&self) {
+        let resume = Settings::current().resume.clone();
+        let Some(uri) = resume.uri else {
+            return;
+        };
+        if !self
+            .queue
+            .lock()
+            .unwrap()
+            .restore(&uri, resume.index.unwrap_or_default())
+        {
+            log::debug!("{uri} is not in the playlist anymore, not resuming it");
+            return;
+        }
+
+        // Nothing to load if we were stopped, being on the right track in the playlist is enough
+        if resume.playing || resume.position_ms > 0 {
+            log::info!("Resuming {uri} at {}ms", resume.position_ms);
+            match SpotifyUri::from_uri(&uri) {
+                Ok(uri) => self.load(uri, false, resume.position_ms),
+                Err(e) => log::warn!("Could not resume {uri} ({e:?})"),
+            }
+        }
+    }
+
+    pub fn get_queue_position(&self) -> Option<usize> {
+        self.queue.lock().unwrap().current_index()
+    }
+
+    pub fn set_queue_uris(&self, uris: Vec<String>) {
+        self.queue.lock().unwrap().set_uris(uris);
+    }
+
+    pub fn set_shuffle(&self, shuffle: bool) {
+        self.queue.lock().unwrap().set_shuffle(shuffle);
+    }
+
+    pub fn set_repeat(&self, repeat: RepeatMode) {
+        self.queue.lock().unwrap().set_repeat(repeat);
+    }
+
+    pub fn next(&self) {
+        if self.with_remote_spirc(Spirc::next) {
+            return;
+        }
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(uri) = queue.next(false) {
+            self.switch_to(uri);
+        }
+    }
+
+    pub fn previous(&self) {
+        if self.with_remote_spirc(Spirc::prev) {
+            return;
+        }
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(uri) = queue.previous() {
+            self.switch_to(uri);
+        }
+    }
+
+    /// Moves the queue to an entry and plays it, or with `play` unset only plays it if something was already playing.
+    pub fn jump_to(&self, index: usize, play: bool) -> Result<(), PlayError> {
+        let mut queue = self.queue.lock().unwrap();
+        let uri = queue
+            .jump_to(index)
+            .ok_or(PlayError::QueueIndexOutOfRange(index))?;
+        if play {
+            self.load_queued(uri);
+        } else {
+            self.switch_to(uri);
+        }
+        Ok(())
+    }
+
+    /// Plays the track the queue moved to, unless we are stopped in which case it is just selected.
+    fn switch_to(&self, uri: &str) {
+        if self.playback.lock().unwrap().uri.is_some() {
+            self.load_queued(uri);
+        }
     }
 
-    pub async fn pause(&mut self) -> Result<(), PlayError> {
+    fn load_queued(&self, uri: &str) {
+        match SpotifyUri::from_uri(uri) {
+            Ok(uri) => {
+                self.sink_state.crossfader.lock().unwrap().begin_mix();
+                self.load(uri, true, 0);
+            }
+            Err(e) => log::error!("Could not play queued {uri} ({e:?})"),
+        }
+    }
+
+    pub fn get_queue_channel(&self) -> UnboundedReceiver<QueuePosition> {
+        self.queue.lock().unwrap().subscribe()
+    }
+
+    pub async fn pause(&self) -> Result<(), PlayError> {
         log::debug!("Pause!");
-        self.player.pause();
+        self.fade_out(Fader::fade_out).await;
+        if !self.with_remote_spirc(Spirc::pause) {
+            self.player().pause();
+        }
         Ok(())
     }
 
-    pub async fn stop(&mut self) -> Result<(), PlayError> {
+    pub async fn stop(&self) -> Result<(), PlayError> {
         log::debug!("Stop!");
-        self.player.stop();
+        self.fade_out(Fader::fade_out).await;
+        self.player().stop();
+        self.sink_state.fader.lock().unwrap().reset();
         Ok(())
     }
 
+    /// Fades out (if anything is playing) and waits for it to finish.
+    async fn fade_out(&self, fade: fn(&mut Fader) -> u32) {
+        if !self.playback.lock().unwrap().playing {
+            return;
+        }
+        let fade_ms = fade(&mut self.sink_state.fader.lock().unwrap());
+        tokio::time::sleep(Duration::from_millis(fade_ms as u64)).await;
+    }
+
     pub async fn get_track_ids(
         &self,
         playlist_uri: SpotifyUri,
     ) -> Result<Vec<SpotifyUri>, PlayError> {
         match playlist_uri {
-            SpotifyUri::Playlist { .. } => Ok(Playlist::get(&self.session.inner, &playlist_uri)
+            SpotifyUri::Playlist { .. } => Ok(Playlist::get(&self.session.get(), &playlist_uri)
                 .await
                 .map_err(|e| PlayError::MetadataError { e })?
                 .contents
                 .items
                 .iter()
                 .filter(|item| {
-                    let is_track = matches!(&item.id, SpotifyUri::Track { .. });
+                    let is_playable = matches!(
+                        &item.id,
+                        SpotifyUri::Track { .. } | SpotifyUri::Episode { .. }
+                    );
 
-                    is_track
+                    is_playable
                 })
                 .map(|item| &item.id)
                 .cloned()
                 .collect()),
-            SpotifyUri::Album { .. } => Ok(Album::get(&self.session.inner, &playlist_uri)
+            SpotifyUri::Album { .. } => Ok(Album::get(&self.session.get(), &playlist_uri)
                 .await
                 .map_err(|e| PlayError::MetadataError { e })?
                 .tracks()
                 .cloned()
                 .collect()),
+            SpotifyUri::Show { .. } => Ok(Show::get(&self.session.get(), &playlist_uri)
+                .await
+                .map_err(|e| PlayError::MetadataError { e })?
+                .episodes
+                .iter()
+                .cloned()
+                .collect()),
+            SpotifyUri::Artist { .. } => self.get_artist_track_ids(&playlist_uri).await,
             _ => {
                 log::warn!("Trying to get playlist tracks from an id that is not a playlist");
                 Ok(vec![])
@@ -239,39 +1328,328 @@ impl SpotifyPlayer {
         }
     }
 
-    pub async fn get_track(&mut self, track_uri: SpotifyUri) -> Result<Track, PlayError> {
-        match track_uri {
-            SpotifyUri::Track { .. } => {
-                log::debug!("Getting track data: {:?}", track_uri);
-                //TODO: Check why we get `TrackMetadataError { e: Error { kind: Internal, error: ErrorMessage("channel closed") } }` here after leaving the mac in standby for a while.
-                Track::get(&self.session.inner, &track_uri)
+    async fn get_artist_track_ids(
+        &self,
+        artist_uri: &SpotifyUri,
+    ) -> Result<Vec<SpotifyUri>, PlayError> {
+        let session = self.session.get();
+        let artist = Artist::get(&session, artist_uri)
+            .await
+            .map_err(|e| PlayError::MetadataError { e })?;
+
+        let artist_expansion = Settings::current().playlist.artist_expansion;
+        match artist_expansion {
+            ArtistExpansion::TopTracks => {
+                let country = session.country();
+                Ok(artist.top_tracks_for_country(&country).to_vec())
+            }
+            ArtistExpansion::Discography => {
+                let mut track_ids = vec![];
+                for album_uri in artist
+                    .albums_current()
+                    .chain(artist.singles_current())
+                    .chain(artist.compilations_current())
+                {
+                    let album = Album::get(&session, album_uri)
+                        .await
+                        .map_err(|e| PlayError::MetadataError { e })?;
+                    track_ids.extend(album.tracks().cloned());
+                }
+                Ok(track_ids)
+            }
+        }
+    }
+
+    pub async fn get_track(&self, track_uri: SpotifyUri) -> Result<Track, PlayError> {
+        (self.track_lookup)(self.session.get(), track_uri).await
+    }
+
+    pub fn is_session_invalid(&self) -> bool {
+        self.session.is_invalid()
+    }
+
+    /// Replaces a lost session, and the player using it, carrying on with the track where it was.
+    pub async fn reconnect(&self) -> Result<(), SessionError> {
+        // Spotify Connect connects a new session itself when it is restarted along with the player
+        if self.connect.lock().unwrap().spirc.is_none() {
+            self.session.reconnect().await?;
+        }
+        self.rebuild_player().await
+    }
+
+    /// If the other account can't connect, we carry on with the current one.
+    pub async fn switch_account(&self, name: &str) -> Result<(), SessionError> {
+        self.stop_connect();
+        self.stop_for_session_change().await;
+        let switched = self.session.switch_account(name).await;
+        self.after_account_change().await?;
+        switched
+    }
+
+    /// Nothing changes until the other account has logged in, so cancelling the login leaves playback alone.
+    pub async fn add_account(&self, app: &AppHandle) -> Result<(), SessionError> {
+        let login = SpotifySession::get_new_login(app).await?;
+        self.stop_connect();
+        self.stop_for_session_change().await;
+        let added = self.session.add_account(login).await;
+        self.after_account_change().await?;
+        added
+    }
+
+    pub async fn logout(&self, app: &AppHandle) -> Result<(), SessionError> {
+        self.stop_connect();
+        self.stop_for_session_change().await;
+        self.session.logout(app).await?;
+        self.after_account_change().await
+    }
+
+    async fn after_account_change(&self) -> Result<(), SessionError> {
+        let connect_enabled = Settings::current().connect.enabled;
+        if connect_enabled {
+            self.start_connect().await
+        } else {
+            self.rebuild_player().await
+        }
+    }
+
+    /// What was playing might not be available to the next account (or belongs to the previous Connect client), so don't carry it over.
+    async fn stop_for_session_change(&self) {
+        if let Err(e) = self.stop().await {
+            log::warn!("Could not stop before changing sessions ({e:?})");
+        }
+        // Don't wait for the stopped event, the player is rebuilt before it arrives
+        *self.playback.lock().unwrap() = PlaybackState::default();
+    }
+
+    /// Images are looked up in (and added to) the `covers` directory next to the settings before asking Spotify.
+    pub async fn get_cover_art(
+        &self,
+        file_id: FileId,
+        size: CoverSize,
+    ) -> Result<Vec<u8>, PlayError> {
+        let file_name = file_id
+            .to_base16()
+            .map_err(|e| PlayError::CoverArtError { e })?;
+        let cache_path = get_config_dir().map(|config_dir| {
+            config_dir
+                .join("covers")
+                .join(format!("{size:?}"))
+                .join(file_name)
+        });
+        if let Some(cache_path) = &cache_path
+            && let Ok(image) = std::fs::read(cache_path)
+        {
+            return Ok(image);
+        }
+
+        log::debug!("Getting cover art: {file_id:?}");
+        let image = self
+            .session
+            .get()
+            .spclient()
+            .get_image(&file_id)
+            .await
+            .map_err(|e| PlayError::CoverArtError { e })?
+            .to_vec();
+
+        if let Some(cache_path) = &cache_path {
+            let written = cache_path
+                .parent()
+                .map(std::fs::create_dir_all)
+                .unwrap_or(Ok(()))
+                .and_then(|_| std::fs::write(cache_path, &image));
+            if let Err(e) = written {
+                log::warn!("Could not cache cover art in {cache_path:?} ({e:?})");
+            }
+        }
+        Ok(image)
+    }
+
+    pub async fn get_episode(&self, episode_uri: SpotifyUri) -> Result<Episode, PlayError> {
+        match episode_uri {
+            SpotifyUri::Episode { .. } => {
+                log::debug!("Getting episode data: {:?}", episode_uri);
+                Episode::get(&self.session.get(), &episode_uri)
                     .await
                     .map_err(|e| PlayError::MetadataError { e })
             }
-            _ => Err(PlayError::GettingTrackForNonTrackUri(track_uri)),
+            _ => Err(PlayError::GettingEpisodeForNonEpisodeUri(episode_uri)),
         }
     }
 
-    pub fn set_volume(&mut self, volume: u16) {
-        self.volume
+    /// Sets the volume without remembering it, for fading in and out.
+    pub fn set_faded_volume(&self, volume: u16) {
+        self.sink_state
+            .volume
             .store(volume, std::sync::atomic::Ordering::Relaxed);
-        self.session.cache.save_volume(volume);
+    }
+
+    pub fn set_volume(&self, volume: u16) {
+        self.sink_state
+            .volume
+            .store(volume, std::sync::atomic::Ordering::Relaxed);
+        self.session.cache().save_volume(volume);
     }
 
     pub fn get_volume(&self) -> u16 {
-        self.volume.load(std::sync::atomic::Ordering::Relaxed)
+        self.sink_state
+            .volume
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_balance(&self, balance: i16) {
+        self.sink_state
+            .balance
+            .store(balance, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Switches audio backend and/or device, the sink picks it up with the next packet it writes.
+    pub fn set_audio_output(&self, output: OutputSettings) {
+        *self.sink_state.output.lock().unwrap() = output;
+    }
+
+    /// Starts recording, beginning with whatever is playing right now.
+    pub fn start_recording(&self, output_directory: PathBuf) -> Result<(), RecordingError> {
+        self.sink_state
+            .recorder
+            .lock()
+            .unwrap()
+            .start(output_directory)?;
+        let playing_uri = {
+            let playback = self.playback.lock().unwrap();
+            playback.uri.clone().filter(|_| playback.playing)
+        };
+        match playing_uri {
+            Some(uri) => self.record_track(&uri),
+            None => Ok(()),
+        }
+    }
+
+    pub fn stop_recording(&self) {
+        self.sink_state.recorder.lock().unwrap().stop();
+    }
+
+    /// Moves the recording on to a new file for the track unless we are already recording it.
+    /// The file is started right away so nothing is lost, if the title isn't cached it is named later.
+    pub fn record_track(&self, uri: &SpotifyUri) -> Result<(), RecordingError> {
+        let uri_string = uri.to_uri().expect("a valid uri");
+        let cached_title = MetadataCache::current()
+            .get_stale(&uri_string)
+            .map(|metadata| metadata.title());
+        {
+            let mut recorder = self.sink_state.recorder.lock().unwrap();
+            if !recorder.is_active() || recorder.is_recording_track(&uri_string) {
+                return Ok(());
+            }
+            recorder.start_track(&uri_string, cached_title.as_deref())?;
+        }
+        if cached_title.is_some() {
+            return Ok(());
+        }
+
+        let session = self.session.get();
+        let recorder = self.sink_state.recorder.clone();
+        let track_lookup = self.track_lookup.clone();
+        let uri = uri.clone();
+        tauri::async_runtime::spawn(async move {
+            let title = match uri {
+                SpotifyUri::Episode { .. } => Episode::get(&session, &uri)
+                    .await
+                    .map(|episode| EpisodeMetadata::from(&episode).title())
+                    .map_err(|e| PlayError::MetadataError { e }),
+                _ => track_lookup(session, uri)
+                    .await
+                    .map(|track| TrackMetadata::from(&track).title()),
+            };
+            match title {
+                Ok(title) => recorder.lock().unwrap().set_title(&uri_string, title),
+                Err(e) => {
+                    log::warn!(
+                        "Could not get metadata for recording, keeping it named by uri ({e:?})"
+                    )
+                }
+            }
+        });
+        Ok(())
+    }
+
+    pub fn finish_recorded_track(&self) {
+        self.sink_state.recorder.lock().unwrap().finish_track();
+    }
+
+    pub fn set_playback_rate(&self, rate: f32) {
+        self.sink_state
+            .time_stretcher
+            .lock()
+            .unwrap()
+            .set_rate(rate);
+    }
+
+    pub fn get_playback_rate(&self) -> f32 {
+        self.sink_state.time_stretcher.lock().unwrap().get_rate()
+    }
+
+    pub fn set_fade_duration(&self, duration_ms: u32) {
+        self.sink_state
+            .fader
+            .lock()
+            .unwrap()
+            .set_duration(duration_ms);
+    }
+
+    pub fn set_crossfade(&self, duration_ms: u32) {
+        self.sink_state
+            .crossfader
+            .lock()
+            .unwrap()
+            .set_duration(duration_ms);
+    }
+
+    pub fn set_eq_enabled(&self, enabled: bool) {
+        self.sink_state
+            .equalizer
+            .lock()
+            .unwrap()
+            .set_enabled(enabled);
+    }
+
+    pub fn set_eq_preamp(&self, gain_db: f32) {
+        self.sink_state
+            .equalizer
+            .lock()
+            .unwrap()
+            .set_preamp(gain_db);
     }
 
-    pub fn seek(&self, position_ms: u32) {
-        self.player.seek(position_ms);
+    pub fn set_eq_band(&self, band: usize, gain_db: f32) {
+        self.sink_state
+            .equalizer
+            .lock()
+            .unwrap()
+            .set_band(band, gain_db);
     }
 
-    pub fn take_latest_spectrum(&mut self) -> Vec<(f32, f32)> {
-        self.visualizer.lock().unwrap().take_latest_spectrum()
+    pub async fn seek(&self, position_ms: u32) {
+        self.fade_out(Fader::micro_fade_out).await;
+        self.sink_state.crossfader.lock().unwrap().cancel();
+        self.sink_state.time_stretcher.lock().unwrap().reset();
+        if !self.with_remote_spirc(|spirc| spirc.set_position_ms(position_ms)) {
+            self.player().seek(position_ms);
+        }
+    }
+
+    pub fn take_latest_spectrum(&self) -> Vec<(f32, f32)> {
+        self.sink_state
+            .visualizer
+            .lock()
+            .unwrap()
+            .take_latest_spectrum()
     }
 
     pub fn get_player_event_channel(&self) -> PlayerEventChannel {
-        self.player.get_player_event_channel()
+        let (tx, rx) = unbounded_channel();
+        self.event_subscribers.lock().unwrap().push(tx);
+        rx
     }
 }
 
@@ -280,6 +1658,21 @@ pub enum SessionError {
     #[error("Failed to connect ({e:?}")]
     ConnectError { e: Error },
 
+    #[error("There are no cached credentials to reconnect with")]
+    NoCachedCredentials,
+
+    #[error("The login was cancelled")]
+    LoginCancelled,
+
+    #[error("There is no account called {_0}")]
+    UnknownAccount(String),
+
+    #[error("Could not start zeroconf discovery ({e:?})")]
+    DiscoveryFailed { e: DiscoveryError },
+
+    #[error("Zeroconf discovery ended without receiving credentials")]
+    DiscoveryEnded,
+
     #[error("OAuth error ({e:?}")]
     OauthError { e: OAuthError },
 
@@ -290,10 +1683,115 @@ pub enum SessionError {
     TokenExchangeFailure { e: OAuthError },
 }
 
+pub async fn get_track(session: &Session, track_uri: SpotifyUri) -> Result<Track, PlayError> {
+    match track_uri {
+        SpotifyUri::Track { .. } => {
+            log::debug!("Getting track data: {:?}", track_uri);
+            // Fails with "channel closed" once the session is lost (e.g. after standby), until the supervisor has reconnected
+            Track::get(session, &track_uri)
+                .await
+                .map_err(|e| PlayError::MetadataError { e })
+        }
+        _ => Err(PlayError::GettingTrackForNonTrackUri(track_uri)),
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum PlayError {
     #[error("Failed to fetch metadata ({e:?})")]
     MetadataError { e: Error },
     #[error("Cannot get track for non track id ({_0:?})")]
     GettingTrackForNonTrackUri(SpotifyUri),
+    #[error("Cannot get episode for non episode id ({_0:?})")]
+    GettingEpisodeForNonEpisodeUri(SpotifyUri),
+    #[error("Failed to fetch cover art ({e:?})")]
+    CoverArtError { e: Error },
+    #[error("There is no entry {_0} in the queue")]
+    QueueIndexOutOfRange(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FADE_MS: u32 = 100;
+
+    /// A player that never goes online, with a track lookup that never returns.
+    fn player_with_stalled_lookup(lookup_started: oneshot::Sender<()>) -> SpotifyPlayer {
+        let session = SpotifySession {
+            inner: Mutex::new(Session::new(SessionConfig::default(), None)),
+            cache: Mutex::new(
+                Cache::new(None::<PathBuf>, None, None, None).expect("a cache without files"),
+            ),
+        };
+        let mut settings = Settings::default();
+        settings.player.fade_ms = FADE_MS;
+        // Doesn't need an audio device, and nothing is played anyway
+        settings.output = OutputSettings {
+            backend: Some("pipe".to_string()),
+            device: None,
+        };
+        let lookup_started = Mutex::new(Some(lookup_started));
+        let track_lookup: TrackLookup = Arc::new(move |_: Session, _: SpotifyUri| {
+            if let Some(lookup_started) = lookup_started.lock().unwrap().take() {
+                let _ = lookup_started.send(());
+            }
+            std::future::pending::<Result<Track, PlayError>>().boxed()
+        });
+        SpotifyPlayer::with_parts(
+            session,
+            SinkState::new(&settings),
+            PlayQueue::default(),
+            track_lookup,
+        )
+    }
+
+    /// Starts a track lookup in the background and waits until it is stuck.
+    async fn stall_lookup(
+        player: &Arc<SpotifyPlayer>,
+        lookup_started: oneshot::Receiver<()>,
+    ) -> tokio::task::JoinHandle<Result<Track, PlayError>> {
+        let lookup = tokio::spawn({
+            let player = player.clone();
+            async move {
+                let uri = SpotifyUri::from_uri("spotify:track:0DiWol3AO6WpXZgp0goxAV")
+                    .expect("a valid uri");
+                player.get_track(uri).await
+            }
+        });
+        lookup_started.await.expect("the lookup to start");
+        lookup
+    }
+
+    #[tokio::test]
+    async fn pause_is_not_held_up_by_a_stalled_metadata_lookup() {
+        let (lookup_started, lookup_started_rx) = oneshot::channel();
+        let player = Arc::new(player_with_stalled_lookup(lookup_started));
+        let lookup = stall_lookup(&player, lookup_started_rx).await;
+
+        tokio::time::timeout(Duration::from_secs(1), player.pause())
+            .await
+            .expect("pause to return while the lookup is stalled")
+            .expect("pause to succeed");
+        assert!(!lookup.is_finished());
+        lookup.abort();
+    }
+
+    #[tokio::test]
+    async fn pause_fades_out_while_a_metadata_lookup_is_stalled() {
+        let (lookup_started, lookup_started_rx) = oneshot::channel();
+        let player = Arc::new(player_with_stalled_lookup(lookup_started));
+        // Only a playing player fades out, so this goes through the fader's lock
+        player.playback.lock().unwrap().playing = true;
+        let lookup = stall_lookup(&player, lookup_started_rx).await;
+
+        let started_at = Instant::now();
+        tokio::time::timeout(Duration::from_secs(1), player.pause())
+            .await
+            .expect("pause to return while the lookup is stalled")
+            .expect("pause to succeed");
+        assert!(started_at.elapsed() >= Duration::from_millis(FADE_MS as u64));
+        assert!(!lookup.is_finished());
+        lookup.abort();
+    }
 }
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use tauri::{AppHandle, Emitter};
+
+use crate::spotify::SharedPlayer;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Clone, PartialEq, Serialize)]
+pub enum SessionEvent {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+/// Keeps an eye on the Spotify session and reconnects when it is lost (e.g. after the computer slept or the
+/// network went away), backing off while reconnecting keeps failing.
+pub fn start(app_handle: AppHandle, player: SharedPlayer) {
+    tauri::async_runtime::spawn(async move {
+        let mut retry_delay = CHECK_INTERVAL;
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            if !player.is_session_invalid() {
+                continue;
+            }
+
+            log::warn!("Lost the Spotify session, reconnecting");
+            let _ = app_handle.emit("session", SessionEvent::Connecting);
+            match player.reconnect().await {
+                Ok(()) => {
+                    log::info!("Reconnected to Spotify");
+                    retry_delay = CHECK_INTERVAL;
+                    let _ = app_handle.emit("session", SessionEvent::Connected);
+                }
+                Err(e) => {
+                    log::warn!("Could not reconnect, trying again in {retry_delay:?} ({e:?})");
+                    let _ = app_handle.emit("session", SessionEvent::Disconnected);
+                    tokio::time::sleep(retry_delay).await;
+                    retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,145 @@
+use std::f64::consts::PI;
+
+use librespot::playback::NUM_CHANNELS;
+
+pub const MIN_RATE: f32 = 0.5;
+pub const MAX_RATE: f32 = 2.0;
+
+const CHANNELS: usize = NUM_CHANNELS as usize;
+/// About 23ms, long enough to hold a couple of periods of a low voice
+const WINDOW: usize = 1024;
+const HOP: usize = WINDOW / 2;
+/// How far a window may be moved from where it should be to line up with the previous one
+const TOLERANCE: usize = 256;
+
+/// Changes the playback speed while keeping the pitch, using WSOLA (waveform similarity overlap-add).
+///
+/// The audio is cut into overlapping windows that are taken further apart (or closer together) than
+/// they are put back. Each window is nudged to where it lines up best with the previous one so the seams don't show.
+pub struct TimeStretcher {
+    rate: f32,
+    /// Interleaved samples not used up yet
+    input: Vec<f64>,
+    /// Where the next window should be taken from, in frames into `input`
+    nominal_position: f64,
+    /// Where the audio that followed the previous window starts, the next window should look like it
+    natural_position: Option<usize>,
+    /// The second half of the previous window, waiting for the next one to be added to it
+    overlap: Vec<f64>,
+    window: Vec<f64>,
+}
+
+impl TimeStretcher {
+    pub fn new(rate: f32) -> Self {
+        Self {
+            rate: rate.clamp(MIN_RATE, MAX_RATE),
+            input: Vec::new(),
+            nominal_position: 0.0,
+            natural_position: None,
+            overlap: vec![0.0; HOP * CHANNELS],
+            // A periodic Hann window, two of them half overlapping add up to exactly one
+            window: (0..WINDOW)
+                .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / WINDOW as f64).cos())
+                .collect(),
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: f32) {
+        self.rate = rate.clamp(MIN_RATE, MAX_RATE);
+    }
+
+    pub fn get_rate(&self) -> f32 {
+        self.rate
+    }
+
+    /// Drops whatever is buffered, for when the audio that follows doesn't continue it (e.g. after seeking).
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.nominal_position = 0.0;
+        self.natural_position = None;
+        self.overlap.fill(0.0);
+    }
+
+    /// Takes decoded samples and returns the stretched ones that are ready, which lags the input by about a window.
+    pub fn process(&mut self, samples: Vec<f64>) -> Vec<f64> {
+        if self.rate == 1.0 {
+            if self.natural_position.is_some() {
+                self.reset();
+            }
+            return samples;
+        }
+
+        self.input.extend(samples);
+        let mut output = Vec::with_capacity(self.input.len());
+        loop {
+            let frame_count = self.input.len() / CHANNELS;
+            let nominal_position = self.nominal_position.round() as usize;
+            let search_start = nominal_position.saturating_sub(TOLERANCE);
+            let search_end = nominal_position + TOLERANCE;
+            if search_end + WINDOW > frame_count {
+                break;
+            }
+
+            let position = match self.natural_position {
+                Some(natural_position) => {
+                    self.best_match(natural_position, search_start, search_end)
+                }
+                None => nominal_position,
+            };
+            self.overlap_add(position, &mut output);
+            let natural_position = position + HOP;
+            self.nominal_position += HOP as f64 * self.rate as f64;
+
+            // Keep what the next window might be taken from and the audio it is matched against
+            let consumed = (self.nominal_position.round() as usize)
+                .saturating_sub(TOLERANCE)
+                .min(natural_position);
+            self.input.drain(..consumed * CHANNELS);
+            self.nominal_position -= consumed as f64;
+            self.natural_position = Some(natural_position - consumed);
+        }
+        output
+    }
+
+    fn overlap_add(&mut self, position: usize, output: &mut Vec<f64>) {
+        let segment = &self.input[position * CHANNELS..(position + WINDOW) * CHANNELS];
+        for (frame, (rising, falling)) in self.window[..HOP]
+            .iter()
+            .zip(&self.window[HOP..])
+            .enumerate()
+        {
+            for channel in 0..CHANNELS {
+                let index = frame * CHANNELS + channel;
+                output.push(self.overlap[index] + segment[index] * rising);
+                self.overlap[index] = segment[HOP * CHANNELS + index] * falling;
+            }
+        }
+    }
+
+    /// Finds the window start in `search_start..=search_end` that looks most like what naturally followed the previous window.
+    fn best_match(&self, natural_position: usize, search_start: usize, search_end: usize) -> usize {
+        let mono = |frame: usize| {
+            self.input[frame * CHANNELS..(frame + 1) * CHANNELS]
+                .iter()
+                .sum::<f64>()
+        };
+        let natural: Vec<f64> = (natural_position..natural_position + HOP)
+            .map(mono)
+            .collect();
+        let candidates: Vec<f64> = (search_start..search_end + HOP).map(mono).collect();
+
+        let mut best_position = search_start;
+        let mut best_similarity = f64::MIN;
+        for offset in 0..=search_end - search_start {
+            let candidate = &candidates[offset..offset + HOP];
+            let correlation: f64 = natural.iter().zip(candidate).map(|(a, b)| a * b).sum();
+            let energy: f64 = candidate.iter().map(|sample| sample * sample).sum();
+            let similarity = correlation / energy.sqrt().max(f64::EPSILON);
+            if similarity > best_similarity {
+                best_similarity = similarity;
+                best_position = search_start + offset;
+            }
+        }
+        best_position
+    }
+}
@@ -6,7 +6,7 @@ import { getCurrentWindow } from "@tauri-apps/api/window";
  */
 
 /**
- * @typedef { {playlistWindow: {event: {Ready: null, PlayRequested: null, TrackLoaded: SpotifyTrack, EndReached: null, DragStarted: null, DragEnded: null}}, playerWindow: {event: {CloseRequested: null, UrlsDropped: string[], NextPressed: null, PreviousPressed: null, DragEnded: null }}, player: { event: { 'Paused': { uri: string, position_ms: number}, 'Playing': { uri: string, position_ms: number}, 'Stopped': {uri: string}, 'EndOfTrack': {uri: string}, 'PositionCorrection': { uri: string, position_ms: number}, 'PositionChanged': { uri: string, position_ms: number}, 'Seeked': { uri: string, position_ms: number}} }} } WindowEventTypes
+ * @typedef { {playlistWindow: {event: {Ready: null, TrackLoaded: SpotifyTrack, DragStarted: null, DragEnded: null}}, playerWindow: {event: {CloseRequested: null, UrlsDropped: string[], DragEnded: null }}, player: { event: { 'Paused': { uri: string, position_ms: number}, 'Playing': { uri: string, position_ms: number}, 'Stopped': {uri: string}, 'EndOfTrack': {uri: string}, 'PositionCorrection': { uri: string, position_ms: number}, 'PositionChanged': { uri: string, position_ms: number}, 'Seeked': { uri: string, position_ms: number}, 'StreamInfo': { bitrate: number, sample_rate: number, channels: number }, 'QueuePosition': { index: number, uri: string }, 'Preloading': { uri: string }, 'PlaybackRate': { rate: number }, 'VolumeChanged': { volume: number }}}, scheduler: { event: { 'SleepTimer': { remaining_secs: number }, 'SleepTimerOff': null, 'Alarm': { uri: string, remaining_secs: number }, 'AlarmOff': null } }, session: { event: 'Connecting' | 'Connected' | 'Disconnected' }} } WindowEventTypes
  */
 
 /**
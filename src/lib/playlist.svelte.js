@@ -100,8 +100,7 @@ export class TrackRow extends PlaylistRow {
     }
 
     async play() {
-        await this.loadTrack();
-        await emitWindowEvent("playlistWindow", { PlayRequested: null })
+        await invoke("jump_to", { index: this.playlist.rows.indexOf(this), play: true });
     }
 
     isLoaded() {
@@ -192,26 +191,28 @@ export class Playlist {
         const playerWindowSubscription = subscribeToWindowEvent(
             "playerWindow",
             (event) => {
-                if (event.NextPressed !== undefined) {
-                    this.next(true);
-                } else if (event.PreviousPressed !== undefined) {
-                    this.previous(true);
-                } else if (event.UrlsDropped) {
+                if (event.UrlsDropped) {
                     const urls = event.UrlsDropped;
-                    this.clear().then(() => {
-                        this.addUrls(urls);
-                    });
+                    this.clear()
+                        .then(() => this.addUrls(urls))
+                        .then(() => {
+                            // Keep playing, now from the top of the new playlist
+                            if (this.rows.length > 0) {
+                                invoke("jump_to", { index: 0, play: false });
+                            }
+                        });
                 }
             },
         );
 
+        // The player moves through the playlist by itself, follow along
         const playerSubscription = subscribeToWindowEvent("player", (event) => {
-            if (event.EndOfTrack) {
-                this.next(true).then((endReached) => {
-                    if (endReached) {
-                        emitWindowEvent("playlistWindow", { EndReached: null });
-                    }
-                });
+            if (event.QueuePosition) {
+                const { index, uri } = event.QueuePosition;
+                const row = this.rows[index];
+                if (row?.uri.asString == uri) {
+                    row.loadTrack();
+                }
             }
         });
 
@@ -221,7 +222,13 @@ export class Playlist {
             }
             // Persist after the initial load so any legacy playlist/album URIs
             // get normalised to the individual track URIs they expand into.
-            this.persist();
+            await this.persist();
+            // Show where the player resumed the last session
+            /** @type {number | null} */
+            const index = await invoke("get_queue_position");
+            if (index != null) {
+                await this.rows[index]?.loadTrack();
+            }
         })();
 
         this.dispose = () => {
@@ -242,7 +249,7 @@ export class Playlist {
      * Persist the current playlist as the ordered list of track URIs.
      */
     persist() {
-        invoke("set_uris", { uris: this.rows.map((r) => r.uri.asString) });
+        return invoke("set_uris", { uris: this.rows.map((r) => r.uri.asString) });
     }
 
     /**
@@ -258,13 +265,13 @@ export class Playlist {
     }
 
     /**
-     * Add a URI to the playlist. Playlist/album URIs are unwrapped into their
-     * individual track URIs so the playlist always consists of concrete tracks
+     * Add a URI to the playlist. Playlist/album/show/artist URIs are unwrapped into their
+     * individual track/episode URIs so the playlist always consists of concrete tracks
      * (whose metadata is still lazily loaded as they enter the viewport).
      * @param {SpotifyUri} uri
      */
     async addUri(uri) {
-        if (uri.type == "playlist" || uri.type == "album") {
+        if (uri.type == "playlist" || uri.type == "album" || uri.type == "show" || uri.type == "artist") {
             /** @type {string[]} */
             let trackUris;
             try {
@@ -288,7 +295,7 @@ export class Playlist {
         for (const url of urls) {
             await this.addUri(SpotifyUri.fromUrl(url));
         }
-        this.persist();
+        await this.persist();
     }
 
     /**
@@ -430,43 +437,4 @@ export class Playlist {
         const row = this.focusedRow ?? this.selectedRows[0];
         row?.play();
     }
-
-    /**
-     * @param {number} offset
-     * @param {boolean} skipUnavailable
-     * @returns {Promise<boolean>} true if the end in that direction has been reached
-     */
-    async move(offset, skipUnavailable) {
-        const currRowIndex = this.loadedRow ? this.rows.indexOf(this.loadedRow) : 0;
-        const row = this.rows[currRowIndex + offset];
-        if (!row) {
-            return true;
-        }
-
-        if (row instanceof TrackRow) {
-            const track = await row.loadTrack();
-            if (track && skipUnavailable && track.unavailable) {
-                return await this.move(offset, skipUnavailable);
-            }
-        }
-
-        return false;
-    }
-
-    /**
-     * @param {boolean} skipUnavailable
-     * @returns {Promise<boolean>} true if end has been reached
-     */
-    async next(skipUnavailable = false) {
-        return await this.move(1, skipUnavailable);
-    }
-
-    /**
-     * @param {boolean} skipUnavailable
-     * @returns {Promise<boolean>} true if top has been reached
-     */
-    async previous(skipUnavailable = false) {
-        return await this.move(-1, skipUnavailable);
-    }
-
 }
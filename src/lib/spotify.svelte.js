@@ -33,7 +33,7 @@ export function durationToString(durationInMs) {
 
 export class SpotifyUri {
     /**
-     * @param {"track" | "playlist" | "album"} type
+     * @param {"track" | "playlist" | "album" | "episode" | "show" | "artist"} type
      * @param {string} id
      */
     constructor(type, id) {
@@ -49,10 +49,10 @@ export class SpotifyUri {
         const matches = spotifyUriRe.exec(uriAsString);
         if (matches?.length == 3) {
             const type = matches[1], id = matches[2];
-            if (type == "track" || type == "playlist" || type == "album") {
+            if (type == "track" || type == "playlist" || type == "album" || type == "episode" || type == "show" || type == "artist") {
                 return new SpotifyUri(type, id);
             }
-            throw `'${uriAsString}' is not a valid spotify URI. Only track, playlist and album types are allowed`;
+            throw `'${uriAsString}' is not a valid spotify URI. Only track, playlist, album, episode, show and artist types are allowed`;
         }
 
         throw `${uriAsString} does not match a spotify URI`;
@@ -76,15 +76,77 @@ export class SpotifyUri {
 const spotifyUriRe = /spotify:(.*):(.{22})/;
 const spotifyUrlRe = /https:\/\/open.spotify.com\/(.*)\/(.{22})/;
 
+/**
+ * @typedef {{file_id: string, size: "Small" | "Default" | "Large" | "XLarge", width: number, height: number}} CoverImage
+ */
+
+/**
+ * @param {CoverImage} cover
+ * @returns {Promise<string>} an object URL for the image
+ */
+export async function loadCoverArt(cover) {
+    /** @type {ArrayBuffer} */
+    const image = await invoke("get_cover_art", { fileId: cover.file_id, size: cover.size });
+    return URL.createObjectURL(new Blob([image]));
+}
+
+/**
+ * @typedef {{artist: string, name: string, album: string, duration: number, uri: string, unavailable: boolean, covers: CoverImage[]}} TrackData
+ */
+
+/**
+ * Track metadata requests made in the same tick (e.g. by all rows scrolling into view) are sent as one batch.
+ * @type {{uri: string, resolve: (trackData: TrackData) => void, reject: (e: unknown) => void}[]}
+ */
+let pendingTrackDataRequests = [];
+
+/**
+ * @param {string} uri
+ * @returns {Promise<TrackData>}
+ */
+function requestTrackData(uri) {
+    return new Promise((resolve, reject) => {
+        if (pendingTrackDataRequests.length == 0) {
+            setTimeout(flushTrackDataRequests);
+        }
+        pendingTrackDataRequests.push({ uri, resolve, reject });
+    });
+}
+
+async function flushTrackDataRequests() {
+    const requests = pendingTrackDataRequests;
+    pendingTrackDataRequests = [];
+    try {
+        /** @type {(TrackData | null)[]} */
+        const tracksData = await invoke("get_tracks_metadata", { uris: requests.map((r) => r.uri) });
+        requests.forEach((request, i) => {
+            const trackData = tracksData[i];
+            if (trackData) {
+                request.resolve(trackData);
+            } else {
+                request.reject(`Could not load track ${request.uri}`);
+            }
+        });
+    } catch (e) {
+        requests.forEach((request) => request.reject(e));
+    }
+}
+
 export class SpotifyTrack {
+    /** @type {string | undefined} */
+    album;
+    /** @type {CoverImage[]} */
+    covers = [];
+
     /**
      * @param {string} artist
      * @param {string} name
      * @param {number} durationInMs
      * @param {SpotifyUri} uri
      * @param {boolean} unavailable
+     * @param {Date} [publishedAt] only set for podcast episodes
      */
-    constructor(artist, name, durationInMs, uri, unavailable) {
+    constructor(artist, name, durationInMs, uri, unavailable, publishedAt) {
         this.name = name;
         this.artist = artist;
         this.durationInMs = durationInMs;
@@ -92,6 +154,7 @@ export class SpotifyTrack {
         this.displayName = `${this.artist} - ${this.name}`;
         this.uri = uri;
         this.unavailable = unavailable;
+        this.publishedAt = publishedAt;
     }
 
     /**
@@ -99,8 +162,16 @@ export class SpotifyTrack {
      * @returns {Promise<SpotifyTrack>}
      */
     static async loadFromUri(uri) {
-        /** @type {{artist: string, name: string, duration: number, uri: string, unavailable: boolean}} */
-        const trackData = await invoke("get_track_metadata", { uri: uri.asString });
-        return new SpotifyTrack(trackData.artist, trackData.name, trackData.duration, uri, trackData.unavailable);
+        if (uri.type == "episode") {
+            /** @type {{show: string, name: string, duration: number, published_at: number, uri: string, unavailable: boolean}} */
+            const episodeData = await invoke("get_episode_metadata", { uri: uri.asString });
+            // The show takes the place of the artist
+            return new SpotifyTrack(episodeData.show, episodeData.name, episodeData.duration, uri, episodeData.unavailable, new Date(episodeData.published_at));
+        }
+        const trackData = await requestTrackData(uri.asString);
+        const track = new SpotifyTrack(trackData.artist, trackData.name, trackData.duration, uri, trackData.unavailable);
+        track.album = trackData.album;
+        track.covers = trackData.covers;
+        return track;
     }
 }